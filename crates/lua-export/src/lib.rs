@@ -6,6 +6,10 @@ use quote::{quote, ToTokens};
 use syn::parse_macro_input;
 use syn::{Ident, Item, ItemFn, ItemMod, ReturnType};
 
+// Exported functions can take the Lua context they are called from with an
+// argument of this name, which is not visible from Lua
+const LUA_CONTEXT_ARG: &str = "lua_context";
+
 // Does nothing, just removes the annotation
 #[proc_macro_attribute]
 pub fn lua_export(_attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -65,9 +69,7 @@ fn gen_create_lua_wrapper(function_signs: &[FunctionSignature]) -> Result<ItemFn
         .map(|sign| {
             // Concatenated argument string
             let args = &sign
-                .args
-                .iter()
-                .skip(1) // Skip the config argument
+                .lua_args()
                 .map(|arg| arg.to_string())
                 .collect::<Vec<_>>()
                 .join(", ");
@@ -106,12 +108,18 @@ fn gen_register_lua_api(function_signs: &[FunctionSignature]) -> Result<ItemFn,
             let function_name_str = &sign.name.to_string();
             let function_name = &sign.name;
             let args = &sign.args.iter().skip(1).collect::<Vec<_>>(); // Skip the config argument
+            let lua_args = &sign.lua_args().collect::<Vec<_>>();
+            let context = if lua_args.len() < args.len() {
+                quote!(lua_context)
+            } else {
+                quote!(_)
+            };
             quote!(
                 {
                     let config = config.clone();
                     globals.set(
                         #function_name_str,
-                        lua_context.create_function(move |_, (#(#args),*)| {
+                        lua_context.create_function(move |#context, (#(#lua_args),*)| {
                             #function_name(config.clone(), #(#args),*).to_lua_err()
                         })?
                     )
@@ -144,6 +152,16 @@ struct FunctionSignature {
     _ret: ReturnType,
 }
 
+impl FunctionSignature {
+    // The arguments passed from Lua, without the config and the Lua context
+    fn lua_args(&self) -> impl Iterator<Item = &Ident> {
+        self.args
+            .iter()
+            .skip(1)
+            .filter(|arg| *arg != LUA_CONTEXT_ARG)
+    }
+}
+
 impl From<syn::ItemFn> for FunctionSignature {
     fn from(fun: syn::ItemFn) -> Self {
         let name = fun.sig.ident;
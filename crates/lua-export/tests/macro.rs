@@ -28,6 +28,16 @@ mod lua_export_test {
         Ok(())
     }
 
+    #[lua_export]
+    pub fn baz(
+        _config: Arc<Mutex<RawConfig>>,
+        lua_context: rlua::Context,
+        argum: String,
+    ) -> rlua::Result<String> {
+        let len: usize = lua_context.load("return #...").call(argum)?;
+        Ok(len.to_string())
+    }
+
     #[cfg(test)]
     mod tests {
         use super::{gen_lua_wrapper, register_lua_api};
@@ -38,6 +48,10 @@ mod lua_export_test {
             let lua = rlua::Lua::new();
             let config = Arc::new(Mutex::new(super::RawConfig {}));
             register_lua_api(config, &lua).unwrap();
+            let len = lua
+                .context(|lua_context| lua_context.load(r#"baz("four")"#).eval::<String>())
+                .unwrap();
+            assert_eq!(len, "4");
 
             let root = tempdir::TempDir::new("test_lua_export").unwrap();
             let path = root.path().join("lib.lua");
@@ -56,6 +70,10 @@ mod lua_export_test {
                     return bar(_argum)
                 end
 
+                function M.baz(argum)
+                    return baz(argum)
+                end
+
                 return M
                 "
             );
@@ -1,68 +1,305 @@
 use std::{
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
 
-use super::opt::{Generate, Run};
+use super::opt::{Clean, Generate, Rollback, Run, Uninstall, Watch};
 use crate::{
-    conductor::{config::Config, engine::Engine},
+    conductor::{config::Config, engine::EngineRegistry, Conductor, ConductorOptions},
     config::rawconfig::RawConfig,
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
 use rlua::Lua;
 
 pub(super) fn run(run: &Run) -> Result<()> {
-    // Drop the arked config at the end...?
-    // TODO: Hide all of this inside the config module, so we can reuse it. Then change visibilities
-    let config = RawConfig::default();
-    let arked_config = Arc::new(Mutex::new(config)); // Cant clone here, because I dont want a copy
-    {
-        // TODO: @important Captures the Arc<Mutex<TemplarConfig>>. If I want to
-        // reuse the lua context for my engine (i.e. global lua variables), I will
-        // wneed to clone it.
-        // NOTE: This should not be a problem, as global variables should be instead implemented via EngineArgs, using some sort of
-        // abstraction layer over the Engine, so that engines that dont use lua can be implemented
+    let config_path = config_path(run.config_path.as_ref())?;
+    let options = ConductorOptions {
+        dry_run: run.dry_run,
+        jobs: run.jobs,
+        force: run.force,
+        prune: run.prune,
+    };
+    let conductor = load_conductor(&config_path, run.profile.as_deref(), options)?;
+    conductor.conduct()?;
+    Ok(())
+}
 
-        let lua = Lua::new();
-        super::config::api::register_lua_api(arked_config.clone(), &lua)?;
+pub(super) fn rollback(rollback: &Rollback) -> Result<()> {
+    let config_path = config_path(rollback.config_path.as_ref())?;
+    load_conductor(&config_path, None, ConductorOptions::default())?.rollback()
+}
 
-        let config_path = if let Some(path) = run.config_path.as_ref() {
-            PathBuf::from(path).canonicalize()?
-        } else {
-            let base = match std::env::var("TEMPLAR_CONFIG") {
-                Ok(path) => PathBuf::from(path),
-                Err(_) => {
-                    let home = std::env::var("HOME")?;
-                    PathBuf::from(
-                        std::env::var("XDG_CONFIG_HOME")
-                            .unwrap_or_else(|_| format!("{}/.config/templar", home)),
-                    )
+pub(super) fn clean(clean: &Clean) -> Result<()> {
+    let config_path = config_path(clean.config_path.as_ref())?;
+    let options = ConductorOptions {
+        force: clean.force,
+        ..Default::default()
+    };
+    load_conductor(&config_path, clean.profile.as_deref(), options)?.clean(|orphans| {
+        for output in orphans {
+            println!("Orphaned {}", output.display());
+        }
+        Ok(clean.yes || confirm(&format!("Remove {} outputs?", orphans.len()))?)
+    })
+}
+
+pub(super) fn uninstall(uninstall: &Uninstall) -> Result<()> {
+    let config_path = config_path(uninstall.config_path.as_ref())?;
+    let options = ConductorOptions {
+        force: uninstall.force,
+        ..Default::default()
+    };
+    load_conductor(&config_path, uninstall.profile.as_deref(), options)?.uninstall(|outputs| {
+        for output in outputs {
+            println!("Managed {}", output.display());
+        }
+        Ok(uninstall.yes || confirm(&format!("Uninstall {} outputs?", outputs.len()))?)
+    })
+}
+
+/* Asks a yes/no question on stdin, defaulting to no */
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+pub(super) fn watch(watch: &Watch) -> Result<()> {
+    let config_path = config_path(watch.config_path.as_ref())?;
+    let options = ConductorOptions {
+        jobs: watch.jobs,
+        ..Default::default()
+    };
+
+    let (mut raw_config, mut lua) = load_raw_config(&config_path)?;
+    let mut conductor = create_conductor(
+        Config::from_raw_config(raw_config.clone(), watch.profile.as_deref())?,
+        lua.clone(),
+        options.clone(),
+    );
+
+    // Paths are watched before rendering, so that changes made meanwhile are
+    // queued and picked up afterwards
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let mut watched = BTreeMap::new();
+    let mut config_files = loaded_config_files(&config_path, &raw_config);
+    let paths = watched_paths(&config_files, &conductor)?;
+    update_watched(&mut watcher, &mut watched, paths)?;
+    report_error(conductor.conduct());
+
+    loop {
+        // The last render may have included other files
+        let paths = watched_paths(&config_files, &conductor)?;
+        update_watched(&mut watcher, &mut watched, paths)?;
+        let changed = wait_for_changes(&receiver)?;
+        // Directories that were replaced have to be watched again
+        watched.retain(|path, _| !changed.contains(path));
+
+        let config_changed = changed.iter().any(|path| config_files.contains(path));
+        if config_changed {
+            println!("Reloading {:?}", config_path);
+            match load_raw_config(&config_path) {
+                Ok((new_raw_config, new_lua)) => {
+                    (raw_config, lua) = (new_raw_config, new_lua);
+                    config_files = loaded_config_files(&config_path, &raw_config);
+                }
+                Err(e) => {
+                    report_error(Err(e));
+                    continue;
                 }
-            };
-            base.join("config.lua")
+            }
+        }
+
+        // Targets are globbed again, so that new files are picked up
+        conductor = match Config::from_raw_config(raw_config.clone(), watch.profile.as_deref()) {
+            Ok(config) => create_conductor(config, lua.clone(), options.clone()),
+            Err(e) => {
+                report_error(Err(e));
+                continue;
+            }
         };
+        // e.g. new rules
+        let paths = watched_paths(&config_files, &conductor)?;
+        update_watched(&mut watcher, &mut watched, paths)?;
+
+        if config_changed {
+            report_error(conductor.conduct());
+        } else {
+            match conductor.rules_affected_by(&changed) {
+                Ok(rule_ids) if rule_ids.is_empty() => {}
+                Ok(rule_ids) => {
+                    let mut sorted_ids = rule_ids.iter().cloned().collect::<Vec<_>>();
+                    sorted_ids.sort();
+                    println!("Rendering rules: {}", sorted_ids.join(", "));
+                    report_error(conductor.conduct_rules(&rule_ids));
+                }
+                Err(e) => report_error(Err(e)),
+            }
+        }
+    }
+}
+
+/* The config and the files it requires, reloaded when any of them changes */
+fn loaded_config_files(config_path: &Path, raw_config: &RawConfig) -> BTreeSet<PathBuf> {
+    let mut files = raw_config.files.iter().cloned().collect::<BTreeSet<_>>();
+    files.insert(config_path.to_path_buf());
+    files
+}
+
+/*
+ * The directories the conductor's files are in. Directories are watched
+ * instead of files, as editors tend to replace files when saving them
+ */
+fn watched_paths(
+    config_files: &BTreeSet<PathBuf>,
+    conductor: &Conductor,
+) -> Result<BTreeMap<PathBuf, RecursiveMode>> {
+    let mut watched = BTreeMap::new();
+    for file in config_files
+        .iter()
+        .cloned()
+        .chain(conductor.dependencies()?)
+    {
+        if let Some(dir) = file.parent() {
+            watched
+                .entry(dir.to_path_buf())
+                .or_insert(RecursiveMode::NonRecursive);
+        }
+    }
+    let mut rules = conductor.config().rules.iter().collect::<Vec<_>>();
+    while let Some(rule) = rules.pop() {
+        watched.insert(rule.basepath.clone(), RecursiveMode::Recursive);
+        rules.extend(rule.rules.iter());
+    }
+    Ok(watched)
+}
 
-        super::config::rawconfig::require_config(&lua, config_path)?;
+/* Stops watching the paths that are not needed anymore, and starts on the new ones */
+fn update_watched(
+    watcher: &mut impl Watcher,
+    watched: &mut BTreeMap<PathBuf, RecursiveMode>,
+    paths: BTreeMap<PathBuf, RecursiveMode>,
+) -> Result<()> {
+    for (path, mode) in watched.iter() {
+        if paths.get(path) != Some(mode) {
+            // Fails if the directory was removed, which already unwatched it
+            let _ = watcher.unwatch(path);
+        }
+    }
+    for (path, mode) in &paths {
+        if watched.get(path) != Some(mode) {
+            watcher
+                .watch(path, *mode)
+                .with_context(|| format!("Failed to watch {:?}", path))?;
+        }
     }
+    *watched = paths;
+    Ok(())
+}
 
-    // We know that, at this point, the other references to the inner contents
-    // of the Arc have been dropped, as the Lua context is dropped. Therefore it
-    // is safe to unwrap
-    let config = Arc::try_unwrap(arked_config)
-        .unwrap_or_else(|e| panic!("Failed to unwrap Arc for the config: {:?}", e))
-        .into_inner()
-        .unwrap_or_else(|e| panic!("Failed to unwrap Mutex for the config: {:?}", e));
-
-    let templar_config = Config::from_raw_config(config)?;
-
-    // TODO: At the moment all of these are being hardcoded
-    let parser_config = super::conductor::trebuchet::parser::ParserConfig::default();
-    let engine = super::conductor::trebuchet::Trebuchet::new(parser_config);
-    let _conductor = super::conductor::Conductor::new(Box::new(engine), templar_config);
-    // conductor.conduct()?;
+/* Blocks until any of the watched files changes */
+fn wait_for_changes(
+    receiver: &mpsc::Receiver<notify::Result<notify::Event>>,
+) -> Result<Vec<PathBuf>> {
+    let mut changed = BTreeSet::new();
+    while changed.is_empty() {
+        add_changes(&mut changed, receiver.recv()?)?;
+    }
+    // Saving a file usually produces a burst of events, wait for it to end
+    while let Ok(event) = receiver.recv_timeout(Duration::from_millis(100)) {
+        add_changes(&mut changed, event)?;
+    }
+    Ok(changed.into_iter().collect())
+}
+
+fn add_changes(
+    changed: &mut BTreeSet<PathBuf>,
+    event: notify::Result<notify::Event>,
+) -> Result<()> {
+    let event = event?;
+    // Rendering reads the templates, which would otherwise trigger a new render
+    if !event.kind.is_access() {
+        changed.extend(event.paths);
+    }
     Ok(())
 }
 
+fn report_error(result: Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Error: {:?}", e);
+    }
+}
+
+fn load_conductor(
+    config_path: &Path,
+    profile: Option<&str>,
+    options: ConductorOptions,
+) -> Result<Conductor> {
+    let (raw_config, lua) = load_raw_config(config_path)?;
+    let config = Config::from_raw_config(raw_config, profile)?;
+    Ok(create_conductor(config, lua, options))
+}
+
+fn create_conductor(config: Config, lua: Arc<Mutex<Lua>>, options: ConductorOptions) -> Conductor {
+    Conductor::new(EngineRegistry::default(), config, options).with_lua(lua)
+}
+
+fn config_path(config_path: Option<&PathBuf>) -> Result<PathBuf> {
+    let config_path = if let Some(path) = config_path {
+        PathBuf::from(path)
+    } else {
+        let base = match std::env::var("TEMPLAR_CONFIG") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let home = std::env::var("HOME")?;
+                PathBuf::from(
+                    std::env::var("XDG_CONFIG_HOME")
+                        .unwrap_or_else(|_| format!("{}/.config/templar", home)),
+                )
+            }
+        };
+        base.join("config.lua")
+    };
+    config_path
+        .canonicalize()
+        .with_context(|| format!("Could not find the config file {:?}", config_path))
+}
+
+/*
+ * Runs the config, returning the config it built along with the Lua state it
+ * ran in. The state is kept alive, as the rule hooks are functions inside it
+ * and templates are rendered in it. The config only ever runs once per load
+ */
+fn load_raw_config(config_path: &Path) -> Result<(RawConfig, Arc<Mutex<Lua>>)> {
+    // TODO: Hide all of this inside the config module, so we can reuse it. Then change visibilities
+    let config = RawConfig::default();
+    let arked_config = Arc::new(Mutex::new(config)); // Cant clone here, because I dont want a copy
+
+    // NOTE: Global variables should be implemented via EngineArgs, using some sort of
+    // abstraction layer over the Engine, so that engines that dont use lua can be implemented
+    let lua = Lua::new();
+    super::config::api::register_lua_api(arked_config.clone(), &lua)?;
+    super::config::rawconfig::require_config(&lua, config_path.to_path_buf())?;
+
+    // The Lua API still holds a reference to the config, so it is copied out
+    let mut config = arked_config
+        .lock()
+        .unwrap_or_else(|e| panic!("Failed to lock the Mutex for the config: {:?}", e))
+        .clone();
+    config.files = super::config::rawconfig::required_files(&lua)?;
+    for warning in &config.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok((config, Arc::new(Mutex::new(lua))))
+}
+
 pub(super) fn generate(generate: &Generate) -> Result<()> {
     let file_path = generate.file_path.clone().unwrap_or("./templar.lua".into());
     super::config::api::gen_lua_wrapper(&file_path)?;
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use super::manifest::hash;
+
+/* What is deployed to the output path of a target */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Artifact {
+    /* A rendered or copied file */
+    File {
+        contents: Vec<u8>,
+        /* Other files the contents were generated from (i.e. includes) */
+        dependencies: Vec<PathBuf>,
+    },
+    /* A symlink pointing back to the template */
+    Symlink(PathBuf),
+}
+
+impl Artifact {
+    pub(super) fn hash(&self) -> String {
+        match self {
+            Artifact::File { contents, .. } => hash(contents),
+            Artifact::Symlink(target) => hash_symlink(target),
+        }
+    }
+
+    pub(super) fn dependencies(&self) -> &[PathBuf] {
+        match self {
+            Artifact::File { dependencies, .. } => dependencies,
+            Artifact::Symlink(_) => &[],
+        }
+    }
+}
+
+/*
+ * Hash of whatever is at an output path. Symlinks are not followed, so that a
+ * symlink and a copy of the file it points to are told apart.
+ */
+pub(super) fn hash_output(path: &Path) -> Option<String> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    if metadata.file_type().is_symlink() {
+        std::fs::read_link(path)
+            .ok()
+            .map(|target| hash_symlink(&target))
+    } else {
+        std::fs::read(path).ok().map(|contents| hash(&contents))
+    }
+}
+
+fn hash_symlink(target: &Path) -> String {
+    hash(format!("symlink:{}", target.display()).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_hash_output() {
+        let root = TempDir::new("test_hash_output").unwrap();
+        let file = root.path().join("file");
+        let link = root.path().join("link");
+        std::fs::write(&file, "contents").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let copy = Artifact::File {
+            contents: b"contents".to_vec(),
+            dependencies: vec![],
+        };
+        let symlink = Artifact::Symlink(file.clone());
+        assert_eq!(hash_output(&file), Some(copy.hash()));
+        assert_eq!(hash_output(&link), Some(symlink.hash()));
+        assert_ne!(copy.hash(), symlink.hash());
+        assert_eq!(hash_output(&root.path().join("missing")), None);
+    }
+}
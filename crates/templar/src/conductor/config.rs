@@ -1,30 +1,131 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use glob::glob;
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
-use crate::config::{rawconfig::RawConfig, rawrule::RawRule};
+use super::{
+    engine::DEFAULT_ENGINE,
+    exclude::Excludes,
+    manifest::{hash, hash_file},
+    trebuchet::parser::ParserConfig,
+};
+use crate::config::{
+    hook::{Hook, Hooks},
+    rawconfig::{EngineArgs, RawConfig},
+    rawprofile::RawProfile,
+    rawrule::{RawRename, RawRule, RawSyntax},
+};
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     pub rules: Vec<Rule>,
     pub dest_base: PathBuf,
-    //pub engine_args: EngineArgs,
+    /* Where includes are looked up when they are not next to the including template */
+    pub include_dirs: Vec<PathBuf>,
+    /* The global ones and the profile's, already merged into every rule's */
+    pub engine_args: EngineArgs,
+    /* Changes whenever config.lua or a file it requires does */
+    pub config_hash: String,
 }
 
 impl Config {
-    pub(crate) fn from_raw_config(raw_config: RawConfig) -> Result<Self> {
+    /* The profile is picked by name, or else by the hostname, if any matches it */
+    pub(crate) fn from_raw_config(
+        mut raw_config: RawConfig,
+        profile: Option<&str>,
+    ) -> Result<Self> {
+        let mut engine_args = raw_config.engine_args.clone();
+        if let Some((name, profile)) = select_profile(&raw_config, profile, hostname())? {
+            engine_args = engine_args.merged(&profile.engine_args);
+            for (id, enabled) in profile.rules {
+                let found = set_enabled(&mut raw_config.rules, &id, enabled);
+                anyhow::ensure!(found, "Profile {} refers to the unknown rule {}", name, id);
+            }
+        }
+
+        let inherited = Inherited {
+            engine_args: engine_args.clone(),
+            ..Default::default()
+        };
+        let config_hash = config_hash(&raw_config.files);
         Ok(Config {
             rules: raw_config
                 .rules
                 .into_iter()
-                .map(|raw_rule| Rule::from_raw_rule(raw_rule))
+                .filter(|rule| rule.enabled != Some(false))
+                .map(|rule| Ok(Rule::from_raw_rule_inheriting(rule, &inherited)?.0))
+                .collect::<Result<Vec<_>>>()?,
+            // Relative to the config directory, which is the current directory
+            dest_base: std::env::current_dir()?.join(expand_home(raw_config.dest_base)?),
+            include_dirs: raw_config
+                .include_dirs
+                .into_iter()
+                .map(|dir| Ok(std::env::current_dir()?.join(expand_home(dir)?)))
                 .collect::<Result<Vec<_>>>()?,
-            dest_base: PathBuf::from(raw_config.dest_base),
-            //engine_args: raw_config.engine_args,
+            engine_args,
+            config_hash,
         })
     }
 }
 
+fn config_hash(files: &[PathBuf]) -> String {
+    let hashes = files
+        .iter()
+        .map(|file| format!("{:?} {}", file, hash_file(file).unwrap_or_default()))
+        .collect::<Vec<_>>();
+    hash(hashes.join("\n").as_bytes())
+}
+
+fn select_profile(
+    raw_config: &RawConfig,
+    name: Option<&str>,
+    hostname: Option<String>,
+) -> Result<Option<(String, RawProfile)>> {
+    let found = match name {
+        Some(name) => match raw_config.profiles.get_key_value(name) {
+            Some(found) => Some(found),
+            None => anyhow::bail!(
+                "Unknown profile {:?}, expected one of {:?}",
+                name,
+                raw_config.profiles.keys().collect::<Vec<_>>()
+            ),
+        },
+        None => match hostname {
+            Some(hostname) => raw_config
+                .profiles
+                .iter()
+                .find(|(name, profile)| **name == hostname || profile.hosts.contains(&hostname)),
+            None => None,
+        },
+    };
+    Ok(found.map(|(name, profile)| (name.clone(), profile.clone())))
+}
+
+/* None if it can't be found out, in which case no profile is picked */
+fn hostname() -> Option<String> {
+    let hostname = match std::process::Command::new("hostname").output() {
+        Ok(output) if output.status.success() => String::from_utf8(output.stdout).ok()?,
+        _ => std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?,
+    };
+    let hostname = hostname.trim();
+    (!hostname.is_empty()).then(|| hostname.to_string())
+}
+
+/* Returns whether a rule with the id was found, at any depth */
+fn set_enabled(rules: &mut [RawRule], id: &str, enabled: bool) -> bool {
+    let mut found = false;
+    for rule in rules {
+        if rule.id == id {
+            rule.enabled = Some(enabled);
+            found = true;
+        }
+        found |= set_enabled(&mut rule.rules, id, enabled);
+    }
+    found
+}
+
 impl Default for Config {
     fn default() -> Self {
         let dest_base = PathBuf::from(".")
@@ -34,17 +135,154 @@ impl Default for Config {
         Config {
             rules: vec![],
             dest_base,
-            //engine_args: EngineArgs::default(),
+            include_dirs: vec![],
+            engine_args: EngineArgs::default(),
+            config_hash: String::new(),
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Default)]
+/* How the targets of a rule are deployed to their outputs */
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+pub(crate) enum Deploy {
+    #[default]
+    Render,
+    Copy,
+    /* Symlinks let edits to the output flow back to the template */
+    Symlink,
+    /* Render targets that contain directives, symlink the rest */
+    Auto,
+}
+
+impl std::str::FromStr for Deploy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "render" => Ok(Deploy::Render),
+            "copy" => Ok(Deploy::Copy),
+            "symlink" => Ok(Deploy::Symlink),
+            "auto" => Ok(Deploy::Auto),
+            _ => anyhow::bail!(
+                "Invalid deploy mode {:?}, expected \"render\", \"copy\", \"symlink\" or \"auto\"",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Deploy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Deploy::Render => "render",
+            Deploy::Copy => "copy",
+            Deploy::Symlink => "symlink",
+            Deploy::Auto => "auto",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct Rule {
     pub id: String, // Unique identifier
     pub targets: Vec<PathBuf>,
     pub rules: Vec<Rule>,
     pub basepath: PathBuf,
+    /* Overrides the permission bits of the outputs, which default to the template's */
+    pub mode: Option<u32>,
+    /* Permission bits of the directories created for the outputs */
+    pub dir_mode: Option<u32>,
+    pub deploy: Deploy,
+    pub hooks: Hooks,
+    /* Name of the engine the targets are rendered with */
+    pub engine: String,
+    pub syntax: ParserConfig,
+    /* Merged from the config's and every parent's */
+    pub engine_args: EngineArgs,
+    /* Where the outputs go instead of dest_base, which relative ones are joined to */
+    pub dest: Option<PathBuf>,
+    pub renames: Vec<Rename>,
+}
+
+/* Turns the path of a target relative to the basepath into its output's */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Rename {
+    /* Strips a .tmpl or .tpl extension */
+    StripTmpl,
+    /* Turns the dot_ prefixes of the components into . */
+    DotPrefix,
+    /* A function from the config */
+    Function(Hook),
+}
+
+impl Rename {
+    fn from_raw_rename(raw_rename: RawRename) -> Result<Self> {
+        match raw_rename {
+            RawRename::Builtin(name) => match name.as_str() {
+                "strip_tmpl" => Ok(Rename::StripTmpl),
+                "dot_prefix" => Ok(Rename::DotPrefix),
+                _ => anyhow::bail!(
+                    "Invalid rename {:?}, expected \"strip_tmpl\", \"dot_prefix\" or a function",
+                    name
+                ),
+            },
+            RawRename::Function(function) => Ok(Rename::Function(function)),
+        }
+    }
+
+    /* Functions are called in the Lua state of the config */
+    pub(crate) fn apply(&self, path: &Path, lua: &rlua::Lua) -> Result<PathBuf> {
+        match self {
+            Rename::StripTmpl => match path.extension().and_then(|e| e.to_str()) {
+                Some("tmpl" | "tpl") => Ok(path.with_extension("")),
+                _ => Ok(path.to_path_buf()),
+            },
+            Rename::DotPrefix => Ok(path
+                .iter()
+                .map(
+                    |component| match component.to_str().and_then(|c| c.strip_prefix("dot_")) {
+                        Some(name) => format!(".{}", name).into(),
+                        None => component.to_os_string(),
+                    },
+                )
+                .collect()),
+            Rename::Function(function) => Ok(function
+                .map_path(lua, &path.to_string_lossy())
+                .with_context(|| format!("Failed to rename {:?}", path))?
+                .into()),
+        }
+    }
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            id: String::new(),
+            targets: vec![],
+            rules: vec![],
+            basepath: PathBuf::new(),
+            mode: None,
+            dir_mode: None,
+            deploy: Deploy::default(),
+            hooks: Hooks::default(),
+            engine: DEFAULT_ENGINE.to_string(),
+            syntax: ParserConfig::default(),
+            engine_args: EngineArgs::default(),
+            dest: None,
+            renames: vec![],
+        }
+    }
+}
+
+/* Settings children inherit from their parent rule, unless they override them */
+#[derive(Clone, Debug, Default)]
+struct Inherited {
+    engine: Option<String>,
+    syntax: ParserConfig,
+    engine_args: EngineArgs,
+    dest: Option<String>,
+    rename: Option<Vec<RawRename>>,
 }
 
 impl Rule {
@@ -52,46 +290,197 @@ impl Rule {
     // TODO: This is all relying on PathBuf. Should be changed in somw way, probably. We shouldnt rely on PathBuf until its
     // time to call engine.run()
     pub(super) fn from_raw_rule(raw_rule: RawRule) -> Result<Self> {
-        let rules = raw_rule
+        Ok(Rule::from_raw_rule_inheriting(raw_rule, &Inherited::default())?.0)
+    }
+
+    /*
+     * Also returns the files and directories the globs of the rule and its
+     * descendants matched but excluded, which their parents leave alone too
+     */
+    fn from_raw_rule_inheriting(
+        raw_rule: RawRule,
+        inherited: &Inherited,
+    ) -> Result<(Self, Vec<PathBuf>)> {
+        let syntax = match &raw_rule.syntax {
+            Some(raw_syntax) => apply_syntax(raw_syntax, &inherited.syntax)
+                .with_context(|| format!("Invalid syntax of rule {}", raw_rule.id))?,
+            None => inherited.syntax.clone(),
+        };
+        let inherited = Inherited {
+            engine: raw_rule.engine.or_else(|| inherited.engine.clone()),
+            syntax,
+            engine_args: inherited.engine_args.merged(&raw_rule.engine_args),
+            dest: raw_rule.dest.or_else(|| inherited.dest.clone()),
+            rename: raw_rule.rename.or_else(|| inherited.rename.clone()),
+        };
+        let children = raw_rule
             .rules
             .into_iter()
-            .map(|raw_rule| Rule::from_raw_rule(raw_rule))
+            .map(|rule| {
+                let enabled = rule.enabled != Some(false);
+                let (rule, excluded) = Rule::from_raw_rule_inheriting(rule, &inherited)?;
+                Ok((enabled, rule, excluded))
+            })
             .collect::<Result<Vec<_>>>()?;
 
-        let basepath: String = raw_rule.basepath;
+        let basepath = expand_home(raw_rule.basepath)?;
+        let id = raw_rule.id;
+
+        // Targets are canonicalized, so the basepath has to be too in order to
+        // compute paths relative to it
+        let canonical_basepath = if basepath.is_empty() {
+            PathBuf::from(".")
+        } else {
+            PathBuf::from(&basepath)
+        };
+        let canonical_basepath = canonical_basepath.canonicalize().with_context(|| {
+            format!(
+                "Could not find the basepath {:?} of rule {}",
+                canonical_basepath, id
+            )
+        })?;
+        let excludes = Excludes::new(&canonical_basepath, &raw_rule.exclude)
+            .with_context(|| format!("Invalid rule {}", id))?;
 
-        let children_targets = rules
-            .iter()
-            .flat_map(|r| r.targets.clone())
+        // Targets of disabled children are still left to them, not deployed by
+        // the parent, and so are the files children exclude
+        let mut children_targets = HashSet::new();
+        let mut children_excluded = Vec::new();
+        let mut descendants = Vec::new();
+        for (_, rule, excluded) in &children {
+            descendants.push(rule);
+            children_excluded.extend(excluded.iter().cloned());
+        }
+        while let Some(rule) = descendants.pop() {
+            children_targets.extend(rule.targets.iter().cloned());
+            descendants.extend(&rule.rules);
+        }
+        let rules = children
+            .into_iter()
+            .filter_map(|(enabled, rule, _)| enabled.then_some(rule))
             .collect::<Vec<_>>();
 
-        let mut targets =
-            calc_targets(raw_rule.targets.clone(), basepath.clone()).map_err(|err| {
-                rlua::Error::FromLuaConversionError {
-                    to: "Rule",
-                    from: "LuaValue",
-                    message: Some(err.to_string()),
-                }
-            })?;
+        let mut targets = Vec::new();
+        let mut excluded = Vec::new();
+        for glob in raw_rule.targets {
+            targets.extend(
+                calc_targets(glob, basepath.clone(), &excludes, &mut excluded).map_err(|err| {
+                    rlua::Error::FromLuaConversionError {
+                        to: "Rule",
+                        from: "LuaValue",
+                        message: Some(err.to_string()),
+                    }
+                })?,
+            );
+        }
 
-        let targets = targets
-            .drain_filter(|t| !children_targets.contains(t))
-            .collect();
+        // Files matched by several globs are deployed once
+        let mut seen = HashSet::new();
+        targets.retain(|t| {
+            !children_targets.contains(t)
+                && !children_excluded.iter().any(|path| t.starts_with(path))
+                && seen.insert(t.clone())
+        });
+        excluded.extend(children_excluded);
 
-        let id = raw_rule.id;
+        let mode = raw_rule
+            .mode
+            .as_deref()
+            .map(parse_mode)
+            .transpose()
+            .with_context(|| format!("Invalid rule {}", id))?;
+        let dir_mode = raw_rule
+            .dir_mode
+            .as_deref()
+            .map(parse_mode)
+            .transpose()
+            .with_context(|| format!("Invalid rule {}", id))?;
+        let deploy = match raw_rule.deploy {
+            Some(deploy) => deploy
+                .parse()
+                .with_context(|| format!("Invalid rule {}", id))?,
+            None => Deploy::default(),
+        };
+        let dest = inherited
+            .dest
+            .map(|dest| Ok::<_, anyhow::Error>(PathBuf::from(expand_home(dest)?)))
+            .transpose()?;
+        let renames = inherited
+            .rename
+            .unwrap_or_default()
+            .into_iter()
+            .map(Rename::from_raw_rename)
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Invalid rule {}", id))?;
 
-        Ok(Rule {
+        let rule = Rule {
             id,
             targets,
             rules,
-            basepath: basepath.into(),
-        })
+            basepath: canonical_basepath,
+            mode,
+            dir_mode,
+            deploy,
+            hooks: raw_rule.hooks,
+            engine: inherited
+                .engine
+                .unwrap_or_else(|| DEFAULT_ENGINE.to_string()),
+            syntax: inherited.syntax,
+            engine_args: inherited.engine_args,
+            dest,
+            renames,
+        };
+        Ok((rule, excluded))
     }
 }
 
-fn calc_targets(path: String, basepath: String) -> Result<Vec<PathBuf>> {
-    let home = std::env::var("HOME")?;
-    let path = path.replace('~', home.as_str());
+/* Parses permission bits in octal, like chmod does (e.g. "755" or "0o600") */
+pub(super) fn parse_mode(mode: &str) -> Result<u32> {
+    let digits = mode.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => anyhow::bail!("Invalid mode {:?}, expected octal permission bits", mode),
+    }
+}
+
+/* Only a leading ~ is the home directory, elsewhere it is part of a name */
+pub(super) fn expand_home(path: String) -> Result<String> {
+    if path == "~" || path.starts_with("~/") {
+        let home = std::env::var("HOME").context("Could not find the home directory")?;
+        Ok(format!("{}{}", home, &path[1..]))
+    } else {
+        Ok(path)
+    }
+}
+
+/* Overrides the fields of a syntax that are set in raw_syntax */
+pub(super) fn apply_syntax(raw_syntax: &RawSyntax, syntax: &ParserConfig) -> Result<ParserConfig> {
+    let field = |name: &str, value: &Option<String>, inherited: &String| match value {
+        Some(value) if value.trim().is_empty() => anyhow::bail!("{} must not be empty", name),
+        Some(value) => Ok(value.clone()),
+        None => Ok(inherited.clone()),
+    };
+    Ok(ParserConfig {
+        odelim: field("odelim", &raw_syntax.odelim, &syntax.odelim)?,
+        cdelim: field("cdelim", &raw_syntax.cdelim, &syntax.cdelim)?,
+        comment: field("comment", &raw_syntax.comment, &syntax.comment)?,
+        if_: field("if_", &raw_syntax.if_, &syntax.if_)?,
+        else_: field("else_", &raw_syntax.else_, &syntax.else_)?,
+        end: field("end", &raw_syntax.end, &syntax.end)?,
+        include: field("include", &raw_syntax.include, &syntax.include)?,
+        transform: field("transform", &raw_syntax.transform, &syntax.transform)?,
+        to: field("to", &raw_syntax.to, &syntax.to)?,
+    })
+}
+
+/* Paths the glob matches but excludes leaves out are added to excluded */
+fn calc_targets(
+    path: String,
+    basepath: String,
+    excludes: &Excludes,
+    excluded: &mut Vec<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    let path = expand_home(path)?;
 
     // Concatenate basepath with path
     // TODO: Hacky
@@ -108,25 +497,398 @@ fn calc_targets(path: String, basepath: String) -> Result<Vec<PathBuf>> {
     for path in paths {
         let path = path?;
         if path.is_dir() {
-            targets.extend(expand_dir_rec(path)?);
+            let dir = std::fs::canonicalize(&path)?;
+            if excludes.is_excluded(&dir, true)? {
+                excluded.push(dir);
+            } else {
+                targets.extend(expand_dir_rec(path, excludes, excluded)?);
+            }
         } else if path.is_file() {
-            targets.push(std::fs::canonicalize(path)?);
+            let path = std::fs::canonicalize(path)?;
+            if excludes.is_excluded(&path, false)? {
+                excluded.push(path);
+            } else {
+                targets.push(path);
+            }
         }
     }
     Ok(targets)
 }
 
-fn expand_dir_rec(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+fn expand_dir_rec(
+    dir: impl AsRef<Path>,
+    excludes: &Excludes,
+    excluded: &mut Vec<PathBuf>,
+) -> Result<Vec<PathBuf>> {
     let contents = std::fs::read_dir(dir)?;
 
     let mut targets = Vec::new();
     for entry in contents {
         let path = entry?.path();
         if path.is_dir() {
-            targets.extend(expand_dir_rec(path)?);
+            // Excluded directories (e.g. .git) are not even walked
+            let dir = std::fs::canonicalize(&path)?;
+            if excludes.is_excluded(&dir, true)? {
+                excluded.push(dir);
+            } else {
+                targets.extend(expand_dir_rec(path, excludes, excluded)?);
+            }
         } else if path.is_file() {
-            targets.push(std::fs::canonicalize(path)?);
+            let path = std::fs::canonicalize(path)?;
+            if excludes.is_excluded(&path, false)? {
+                excluded.push(path);
+            } else {
+                targets.push(path);
+            }
         }
     }
     Ok(targets)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0600").unwrap(), 0o600);
+        assert_eq!(parse_mode("0o4755").unwrap(), 0o4755);
+        assert!(parse_mode("789").is_err());
+        assert!(parse_mode("77777").is_err());
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = std::env::var("HOME").unwrap();
+        let expand = |path: &str| expand_home(path.to_string()).unwrap();
+        assert_eq!(expand("~"), home);
+        assert_eq!(expand("~/.config/kitty"), format!("{}/.config/kitty", home));
+        assert_eq!(expand("notes~/x"), "notes~/x");
+        assert_eq!(expand("dir/~"), "dir/~");
+        assert_eq!(expand("~user/x"), "~user/x");
+    }
+
+    #[test]
+    fn test_engine_inheritance() {
+        let root = tempdir::TempDir::new("test_engine_inheritance").unwrap();
+        let basepath = root.path().to_string_lossy().to_string();
+        let rule = |id: &str, engine: Option<&str>, rules: Vec<RawRule>| RawRule {
+            id: id.to_string(),
+            targets: vec!["*".to_string()],
+            basepath: basepath.clone(),
+            engine: engine.map(str::to_string),
+            rules,
+            ..Default::default()
+        };
+        let raw_rule = rule(
+            "root",
+            None,
+            vec![rule(
+                "parent",
+                Some("copy"),
+                vec![
+                    rule("inherits", None, vec![]),
+                    rule("overrides", Some("lua"), vec![]),
+                ],
+            )],
+        );
+
+        let rule = Rule::from_raw_rule(raw_rule).unwrap();
+        assert_eq!(rule.engine, DEFAULT_ENGINE);
+        let parent = &rule.rules[0];
+        assert_eq!(parent.engine, "copy");
+        assert_eq!(parent.rules[0].engine, "copy");
+        assert_eq!(parent.rules[1].engine, "lua");
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let root = tempdir::TempDir::new("test_invalid_settings").unwrap();
+        let raw_rule = RawRule {
+            id: "bad".to_string(),
+            basepath: root.path().to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let error = |raw_rule: RawRule| format!("{:#}", Rule::from_raw_rule(raw_rule).unwrap_err());
+
+        assert_eq!(
+            error(RawRule {
+                mode: Some("rw".to_string()),
+                ..raw_rule.clone()
+            }),
+            "Invalid rule bad: Invalid mode \"rw\", expected octal permission bits"
+        );
+        assert_eq!(
+            error(RawRule {
+                dir_mode: Some("800".to_string()),
+                ..raw_rule.clone()
+            }),
+            "Invalid rule bad: Invalid mode \"800\", expected octal permission bits"
+        );
+        assert_eq!(
+            error(RawRule {
+                deploy: Some("symlnk".to_string()),
+                ..raw_rule
+            }),
+            "Invalid rule bad: Invalid deploy mode \"symlnk\", \
+             expected \"render\", \"copy\", \"symlink\" or \"auto\""
+        );
+    }
+
+    #[test]
+    fn test_syntax_inheritance() {
+        let root = tempdir::TempDir::new("test_syntax_inheritance").unwrap();
+        let basepath = root.path().to_string_lossy().to_string();
+        let syntax = |odelim: &str, cdelim: Option<&str>| RawSyntax {
+            odelim: Some(odelim.to_string()),
+            cdelim: cdelim.map(str::to_string),
+            ..Default::default()
+        };
+        let child = RawRule {
+            id: "child".to_string(),
+            basepath: basepath.clone(),
+            syntax: Some(syntax("{%", None)),
+            ..Default::default()
+        };
+        let parent = RawRule {
+            id: "parent".to_string(),
+            basepath: basepath.clone(),
+            syntax: Some(syntax("<%", Some("%>"))),
+            rules: vec![child],
+            ..Default::default()
+        };
+
+        let rule = Rule::from_raw_rule(parent.clone()).unwrap();
+        assert_eq!(rule.syntax.odelim, "<%");
+        assert_eq!(rule.syntax.cdelim, "%>");
+        assert_eq!(rule.syntax.if_, ParserConfig::default().if_);
+        assert_eq!(rule.rules[0].syntax.odelim, "{%");
+        assert_eq!(rule.rules[0].syntax.cdelim, "%>");
+
+        let invalid = RawRule {
+            syntax: Some(syntax("", None)),
+            ..parent
+        };
+        assert!(Rule::from_raw_rule(invalid).is_err());
+    }
+
+    #[test]
+    fn test_vars_inheritance() {
+        let root = tempdir::TempDir::new("test_vars_inheritance").unwrap();
+        let basepath = root.path().to_string_lossy().to_string();
+        let lua = rlua::Lua::new();
+        let args = |vars: &str| -> EngineArgs {
+            lua.context(|lua_context| lua_context.load(vars).eval())
+                .unwrap()
+        };
+        let child = RawRule {
+            id: "child".to_string(),
+            basepath: basepath.clone(),
+            engine_args: args(r#"{ colors = { bg = "black" }, fonts = { "mono" } }"#),
+            ..Default::default()
+        };
+        let parent = RawRule {
+            id: "parent".to_string(),
+            basepath,
+            engine_args: args(r#"{ user = "parent", size = 1.5 }"#),
+            rules: vec![child],
+            ..Default::default()
+        };
+        let raw_config = RawConfig {
+            rules: vec![parent],
+            engine_args: args(r#"{ user = "global", colors = { bg = "white", fg = "red" } }"#),
+            ..Default::default()
+        };
+
+        let config = Config::from_raw_config(raw_config, None).unwrap();
+        let child = serde_json::to_value(&config.rules[0].rules[0].engine_args.vars).unwrap();
+        assert_eq!(
+            child,
+            serde_json::json!({
+                "user": "parent",
+                "size": 1.5,
+                "colors": { "bg": "black", "fg": "red" },
+                "fonts": ["mono"],
+            })
+        );
+        assert_eq!(
+            config.engine_args,
+            args(r#"{ user = "global", colors = { bg = "white", fg = "red" } }"#)
+        );
+
+        let invalid = lua.context(|lua_context| {
+            lua_context
+                .load("{ f = function() end }")
+                .eval::<EngineArgs>()
+                .is_err()
+        });
+        assert!(invalid);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let root = tempdir::TempDir::new("test_profiles").unwrap();
+        let basepath = root.path().to_string_lossy().to_string();
+        let rule = |id: &str, enabled: Option<bool>, rules: Vec<RawRule>| RawRule {
+            id: id.to_string(),
+            basepath: basepath.clone(),
+            enabled,
+            rules,
+            ..Default::default()
+        };
+        let mut vars = EngineArgs::default();
+        vars.vars.insert("font_size".to_string(), 12.into());
+        let profile = |hosts: Vec<String>, rules: &[(&str, bool)], font_size: u32| {
+            let mut engine_args = EngineArgs::default();
+            engine_args
+                .vars
+                .insert("font_size".to_string(), font_size.into());
+            RawProfile {
+                hosts,
+                engine_args,
+                rules: rules.iter().map(|(id, on)| (id.to_string(), *on)).collect(),
+            }
+        };
+        let raw_config = RawConfig {
+            rules: vec![
+                rule("common", None, vec![rule("child", None, vec![])]),
+                rule("work", Some(false), vec![]),
+            ],
+            engine_args: vars,
+            profiles: [
+                (
+                    "laptop".to_string(),
+                    profile(vec!["laptop-host".to_string()], &[("child", false)], 10),
+                ),
+                (
+                    "desktop".to_string(),
+                    profile(vec![], &[("work", true)], 14),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let ids = |config: &Config| {
+            let mut rules = config.rules.iter().collect::<Vec<_>>();
+            let mut ids = vec![];
+            while let Some(rule) = rules.pop() {
+                ids.push(rule.id.clone());
+                rules.extend(&rule.rules);
+            }
+            ids.sort();
+            ids
+        };
+
+        // Picked by the hostname, or by a profile named after it
+        let picked = |hostname: Option<&str>| {
+            select_profile(&raw_config, None, hostname.map(str::to_string))
+                .unwrap()
+                .map(|(name, _)| name)
+        };
+        assert_eq!(picked(Some("laptop-host")).as_deref(), Some("laptop"));
+        assert_eq!(picked(Some("desktop")).as_deref(), Some("desktop"));
+        assert_eq!(picked(Some("other-host")), None);
+        assert_eq!(picked(None), None);
+
+        let config = Config::from_raw_config(raw_config.clone(), Some("laptop")).unwrap();
+        assert_eq!(ids(&config), ["common"]);
+        assert_eq!(config.rules[0].engine_args.vars["font_size"], 10);
+
+        let config = Config::from_raw_config(raw_config.clone(), Some("desktop")).unwrap();
+        assert_eq!(ids(&config), ["child", "common", "work"]);
+        assert_eq!(config.engine_args.vars["font_size"], 14);
+
+        assert!(Config::from_raw_config(raw_config.clone(), Some("unknown")).is_err());
+        let mut invalid = raw_config;
+        invalid
+            .profiles
+            .get_mut("desktop")
+            .unwrap()
+            .rules
+            .insert("unknown".to_string(), true);
+        assert!(Config::from_raw_config(invalid, Some("desktop")).is_err());
+    }
+
+    #[test]
+    fn test_target_lists() {
+        let root = tempdir::TempDir::new("test_target_lists").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        for file in [
+            "kitty/kitty.conf",
+            "alacritty/alacritty.yml",
+            "alacritty/extra.conf",
+        ] {
+            let path = basepath.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let lua = rlua::Lua::new();
+        let raw_rule = lua
+            .context(|lua_context| {
+                lua_context
+                    .load(&format!(
+                        r#"{{
+                            id = "parent", basepath = {0:?},
+                            targets = {{ "kitty/*.conf", "alacritty/*", "kitty/kitty.conf" }},
+                            rules = {{
+                                {{ id = "child", basepath = {0:?}, targets = "alacritty/*.yml", rules = {{}} }},
+                            }},
+                        }}"#,
+                        basepath
+                    ))
+                    .eval::<RawRule>()
+            })
+            .unwrap();
+        assert_eq!(raw_rule.rules[0].targets, ["alacritty/*.yml"]);
+
+        let rule = Rule::from_raw_rule(raw_rule).unwrap();
+        assert_eq!(
+            rule.targets,
+            [
+                basepath.join("kitty/kitty.conf"),
+                basepath.join("alacritty/extra.conf")
+            ]
+        );
+        assert_eq!(
+            rule.rules[0].targets,
+            [basepath.join("alacritty/alacritty.yml")]
+        );
+    }
+    #[test]
+    fn test_children_excludes() {
+        let root = tempdir::TempDir::new("test_children_excludes").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        for file in [
+            "nvim/init.lua",
+            "nvim/init.lua.swp",
+            "nvim/build/out.lua",
+            "bashrc",
+        ] {
+            let path = basepath.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let child = RawRule {
+            id: "child".to_string(),
+            targets: vec!["nvim".to_string()],
+            basepath: basepath.to_string_lossy().to_string(),
+            exclude: vec!["*.swp".to_string(), "build/".to_string()],
+            ..Default::default()
+        };
+        let parent = RawRule {
+            id: "parent".to_string(),
+            targets: vec!["*".to_string()],
+            basepath: basepath.to_string_lossy().to_string(),
+            rules: vec![child],
+            ..Default::default()
+        };
+        let rule = Rule::from_raw_rule(parent).unwrap();
+        // What the child excludes is not deployed by the parent either
+        assert_eq!(rule.targets, [basepath.join("bashrc")]);
+        assert_eq!(rule.rules[0].targets, [basepath.join("nvim/init.lua")]);
+    }
+}
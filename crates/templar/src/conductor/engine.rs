@@ -1,6 +1,30 @@
 use crate::conductor::trebuchet::parser::ParserConfig;
+use crate::config::rawconfig::EngineArgs;
 use anyhow::Result;
 use dyn_clone::DynClone;
+use rlua::Lua;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{lua_engine::LuaEngine, passthrough::Passthrough, trebuchet::Trebuchet};
+
+/* Engine used by rules that don't pick one */
+pub(crate) const DEFAULT_ENGINE: &str = "trebuchet";
+
+/*
+ * The Lua state templates are evaluated in. The conductor passes the one the
+ * config was loaded with, so that the functions config.lua defines can be
+ * called from templates. Engines sharing it take turns evaluating.
+ */
+pub(crate) type SharedLua = Arc<Mutex<Lua>>;
+
+pub(crate) fn lock_lua(lua: &Mutex<Lua>) -> Result<MutexGuard<'_, Lua>> {
+    lua.lock()
+        .map_err(|_| anyhow::anyhow!("The Lua state is poisoned"))
+}
 
 /*
  * This trait will maybe become a plugin system one day. Will probably need
@@ -10,9 +34,93 @@ use dyn_clone::DynClone;
 
 dyn_clone::clone_trait_object!(Engine);
 
-pub(crate) trait Engine: DynClone {
-    fn new(config: ParserConfig) -> Self
+// Every rendering worker creates its own engines, see EngineRegistry
+pub(crate) trait Engine: DynClone + Send {
+    fn new(config: ParserConfig, lua: SharedLua) -> Self
     where
         Self: Sized;
-    fn run(&self, input: &str) -> Result<String>;
+    fn run(&self, input: &str, context: &RenderContext) -> Result<Rendered>;
+    /* Whether the input contains anything for the engine to render */
+    fn is_template(&self, input: &str) -> bool;
+}
+
+/* Where the template being rendered comes from and is deployed to */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RenderContext {
+    pub id: String,
+    /* Ids of the rules the rule is nested in, outermost first */
+    pub parents: Vec<String>,
+    pub basepath: PathBuf,
+    pub template: PathBuf,
+    pub destination: PathBuf,
+    /* Where includes are looked up when they are not next to the template */
+    pub include_dirs: Vec<PathBuf>,
+    pub args: EngineArgs,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Rendered {
+    pub output: String,
+    /* Other files the output was generated from (i.e. includes) */
+    pub dependencies: Vec<PathBuf>,
+}
+
+pub(crate) type EngineConstructor = fn(ParserConfig, SharedLua) -> Box<dyn Engine>;
+
+/* The engines rules can pick from, by name */
+#[derive(Clone)]
+pub(crate) struct EngineRegistry {
+    engines: BTreeMap<String, EngineConstructor>,
+}
+
+impl EngineRegistry {
+    pub(crate) fn empty() -> Self {
+        EngineRegistry {
+            engines: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn register(&mut self, name: &str, constructor: EngineConstructor) {
+        self.engines.insert(name.to_string(), constructor);
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.engines.contains_key(name)
+    }
+
+    pub(crate) fn create(
+        &self,
+        name: &str,
+        config: ParserConfig,
+        lua: SharedLua,
+    ) -> Result<Box<dyn Engine>> {
+        match self.engines.get(name) {
+            Some(constructor) => Ok(constructor(config, lua)),
+            None => anyhow::bail!(
+                "Unknown engine {:?}, expected one of {:?}",
+                name,
+                self.engines.keys().collect::<Vec<_>>()
+            ),
+        }
+    }
+}
+
+impl Default for EngineRegistry {
+    fn default() -> Self {
+        let mut registry = EngineRegistry::empty();
+        registry.register("trebuchet", boxed::<Trebuchet>);
+        registry.register("copy", boxed::<Passthrough>);
+        registry.register("lua", boxed::<LuaEngine>);
+        registry
+    }
+}
+
+impl std::fmt::Debug for EngineRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.engines.keys()).finish()
+    }
+}
+
+fn boxed<E: Engine + 'static>(config: ParserConfig, lua: SharedLua) -> Box<dyn Engine> {
+    Box::new(E::new(config, lua))
 }
@@ -0,0 +1,129 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+/* Lists files that are never targets, in any directory under a basepath */
+pub(super) const IGNORE_FILE_NAME: &str = ".templarignore";
+
+/*
+ * Decides which files under a basepath are not targets: the ones matching the
+ * exclude globs of the rule, or ignored by a .templarignore. Both follow the
+ * gitignore semantics, and patterns are relative to the basepath and to the
+ * directory of the .templarignore respectively.
+ */
+pub(super) struct Excludes {
+    basepath: PathBuf,
+    globs: Gitignore,
+    /* The .templarignore of every directory looked at so far, possibly empty */
+    ignore_files: RefCell<HashMap<PathBuf, Gitignore>>,
+}
+
+impl Excludes {
+    /* The basepath has to be canonical, like the paths that are checked */
+    pub(super) fn new(basepath: &Path, globs: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(basepath);
+        for glob in globs {
+            builder
+                .add_line(None, glob)
+                .with_context(|| format!("Invalid exclude glob {:?}", glob))?;
+        }
+        Ok(Excludes {
+            basepath: basepath.to_path_buf(),
+            globs: builder.build()?,
+            ignore_files: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub(super) fn is_excluded(&self, path: &Path, is_dir: bool) -> Result<bool> {
+        if path.file_name() == Some(IGNORE_FILE_NAME.as_ref()) {
+            return Ok(true);
+        }
+        // e.g. symlinks to files elsewhere
+        if !path.starts_with(&self.basepath) || path == self.basepath {
+            return Ok(false);
+        }
+        if self
+            .globs
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return Ok(true);
+        }
+
+        // Like with .gitignore, the closest file to the path decides
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.basepath));
+        for dir in dirs {
+            match self.with_ignore_file(dir, |ignore_file| {
+                ignore_file
+                    .matched_path_or_any_parents(path, is_dir)
+                    .map(|_| ())
+            })? {
+                Match::None => continue,
+                Match::Ignore(_) => return Ok(true),
+                Match::Whitelist(_) => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+
+    fn with_ignore_file<T>(&self, dir: &Path, f: impl FnOnce(&Gitignore) -> T) -> Result<T> {
+        if let Some(ignore_file) = self.ignore_files.borrow().get(dir) {
+            return Ok(f(ignore_file));
+        }
+        let path = dir.join(IGNORE_FILE_NAME);
+        let ignore_file = if path.is_file() {
+            match Gitignore::new(&path) {
+                (ignore_file, None) => ignore_file,
+                (_, Some(err)) => return Err(err).with_context(|| format!("Invalid {:?}", path)),
+            }
+        } else {
+            Gitignore::empty()
+        };
+        let result = f(&ignore_file);
+        self.ignore_files
+            .borrow_mut()
+            .insert(dir.to_path_buf(), ignore_file);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_excludes() {
+        let root = TempDir::new("test_excludes").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        std::fs::create_dir_all(basepath.join("nvim/lua")).unwrap();
+        std::fs::write(basepath.join(IGNORE_FILE_NAME), "*.swp\nbuild/\n").unwrap();
+        std::fs::write(basepath.join("nvim").join(IGNORE_FILE_NAME), "!keep.swp\n").unwrap();
+
+        let excludes = Excludes::new(&basepath, &["README*".to_string()]).unwrap();
+        let excluded =
+            |path: &str, is_dir: bool| excludes.is_excluded(&basepath.join(path), is_dir).unwrap();
+        assert!(!excluded("init.lua", false));
+        assert!(excluded("README.md", false));
+        assert!(excluded("nvim/README", false));
+        assert!(excluded("init.lua.swp", false));
+        assert!(excluded("nvim/lua/init.lua.swp", false));
+        // The closest .templarignore decides
+        assert!(!excluded("nvim/keep.swp", false));
+        assert!(excluded("build", true));
+        assert!(excluded("nvim/build/file", false));
+        assert!(excluded(IGNORE_FILE_NAME, false));
+        assert!(!excluded("nvim/lua", true));
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use rlua::prelude::*;
+
+use super::engine::RenderContext;
+use super::trebuchet::run_isolated;
+use crate::config::rawrule::RawSyntax;
+
+/*
+ * Settings a template declares for itself in its header, see
+ * parser::split_header. They override the ones of its rule.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Header {
+    pub syntax: RawSyntax,
+    /* Where the output is written, relative to dest_base */
+    pub destination: Option<String>,
+    pub mode: Option<String>,
+    /* Whether the template is deployed at all */
+    pub enabled: bool,
+}
+
+/* enabled can be a boolean or a function, called with templar.current set */
+pub(super) fn eval_header(lua: &Lua, source: &str, context: &RenderContext) -> Result<Header> {
+    lua.context(|lua_context| {
+        run_isolated(&lua_context, context, || {
+            let table: LuaTable = lua_context.load(source).set_name("header")?.eval()?;
+            let enabled = match table.get::<_, LuaValue>("enabled")? {
+                LuaValue::Nil => true,
+                LuaValue::Boolean(enabled) => enabled,
+                LuaValue::Function(predicate) => predicate.call::<_, bool>(())?,
+                _ => anyhow::bail!("enabled must be a boolean or a function"),
+            };
+            Ok(Header {
+                syntax: RawSyntax::from_lua(LuaValue::Table(table.clone()), lua_context)?,
+                destination: table.get("destination")?,
+                mode: table.get("mode")?,
+                enabled,
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_header() {
+        let lua = Lua::new();
+        let context = RenderContext {
+            id: "rule".to_string(),
+            ..Default::default()
+        };
+        let header = eval_header(
+            &lua,
+            r#"{ odelim = "<%", destination = "dir/file", mode = 600 }"#,
+            &context,
+        )
+        .unwrap();
+        assert_eq!(header.syntax.odelim.as_deref(), Some("<%"));
+        assert_eq!(header.syntax.cdelim, None);
+        assert_eq!(header.destination.as_deref(), Some("dir/file"));
+        assert_eq!(header.mode.as_deref(), Some("600"));
+        assert!(header.enabled);
+
+        let source = r#"{ enabled = function() return templar.current.id ~= "rule" end }"#;
+        assert!(!eval_header(&lua, source, &context).unwrap().enabled);
+        assert!(eval_header(&lua, "{ enabled = 1 }", &context).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+
+use super::engine::{lock_lua, Engine, RenderContext, Rendered, SharedLua};
+use super::trebuchet::{parser::ParserConfig, run_isolated};
+
+/*
+ * Engine for templates that are plain Lua scripts. The string the script
+ * returns is the output.
+ */
+#[derive(Clone)]
+pub(crate) struct LuaEngine {
+    lua: SharedLua,
+}
+
+impl Debug for LuaEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaEngine").finish_non_exhaustive()
+    }
+}
+
+impl Engine for LuaEngine {
+    fn new(_: ParserConfig, lua: SharedLua) -> Self {
+        LuaEngine { lua }
+    }
+
+    fn run(&self, input: &str, context: &RenderContext) -> Result<Rendered> {
+        let name = context.template.to_string_lossy();
+        let output = lock_lua(&self.lua)?.context(|lua_context| {
+            run_isolated(&lua_context, context, || {
+                Ok(lua_context
+                    .load(input)
+                    .set_name(name.as_bytes())?
+                    .eval::<String>()?)
+            })
+        })?;
+        Ok(Rendered {
+            output,
+            dependencies: vec![],
+        })
+    }
+
+    /* Every script is rendered */
+    fn is_template(&self, _: &str) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lua_engine() {
+        let engine = LuaEngine::new(ParserConfig::default(), SharedLua::default());
+        let context = RenderContext {
+            id: "rule".to_string(),
+            ..Default::default()
+        };
+        let output = engine
+            .run(
+                r#"leaked = true; return "id = " .. templar.current.id"#,
+                &context,
+            )
+            .unwrap();
+        assert_eq!(output.output, "id = rule");
+
+        let output = engine.run("return tostring(leaked)", &context).unwrap();
+        assert_eq!(output.output, "nil");
+        assert!(engine.run("return {}", &context).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::artifact::{hash_output, Artifact};
+
+pub(super) const MANIFEST_FILE_NAME: &str = ".templar-manifest";
+
+/*
+ * Record of everything templar wrote into dest_base, keyed by output path.
+ * It's what allows skipping targets whose inputs have not changed since the
+ * last run.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub outputs: BTreeMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Entry {
+    pub template: PathBuf,
+    pub template_hash: String,
+    /* Hashes of the files included by the template, transitively */
+    pub dependencies: BTreeMap<PathBuf, String>,
+    #[serde(flatten)]
+    pub inputs: Inputs,
+    pub output_hash: String,
+    /*
+     * Copy of the file that was at the output path before templar first wrote
+     * it, restored on uninstall
+     */
+    #[serde(default)]
+    pub original: Option<PathBuf>,
+}
+
+/* What an output is rendered from besides its template and includes */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Inputs {
+    pub vars_hash: String,
+    /* Of config.lua and the files it requires, as templates call its functions */
+    #[serde(default)]
+    pub config_hash: String,
+    #[serde(default)]
+    pub engine: String,
+    #[serde(default)]
+    pub deploy: String,
+    /* Of the rule's syntax, or the one its header sets */
+    #[serde(default)]
+    pub syntax_hash: String,
+}
+
+impl Manifest {
+    pub(super) fn path(dest_base: &Path) -> PathBuf {
+        dest_base.join(MANIFEST_FILE_NAME)
+    }
+
+    /* A missing manifest is an empty one (i.e. the first run) */
+    pub(super) fn load(dest_base: &Path) -> Result<Self> {
+        let path = Manifest::path(dest_base);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse the manifest {:?}", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read the manifest {:?}", path)),
+        }
+    }
+
+    pub(super) fn save(&self, dest_base: &Path) -> Result<()> {
+        let path = Manifest::path(dest_base);
+        // Written next to the manifest first, so that it is replaced atomically
+        let temp_path = path.with_extension("tmp");
+        std::fs::create_dir_all(dest_base)?;
+        std::fs::write(&temp_path, serde_json::to_string_pretty(self)?)
+            .and_then(|_| std::fs::rename(&temp_path, &path))
+            .with_context(|| format!("Failed to write the manifest {:?}", path))
+    }
+}
+
+impl Entry {
+    pub(super) fn new(template: &Path, artifact: &Artifact, inputs: Inputs) -> Self {
+        Entry {
+            template: template.to_path_buf(),
+            template_hash: hash_file(template).unwrap_or_default(),
+            dependencies: artifact
+                .dependencies()
+                .iter()
+                .map(|path| (path.clone(), hash_file(path).unwrap_or_default()))
+                .collect(),
+            inputs,
+            output_hash: artifact.hash(),
+            original: None,
+        }
+    }
+
+    /*
+     * Whether rendering the template again would produce the same output that
+     * is already in place
+     */
+    pub(super) fn is_up_to_date(&self, template: &Path, inputs: &Inputs, output: &Path) -> bool {
+        self.template == template
+            && self.inputs == *inputs
+            && hash_file(template).as_deref() == Some(self.template_hash.as_str())
+            && hash_output(output).as_deref() == Some(self.output_hash.as_str())
+            && self
+                .dependencies
+                .iter()
+                .all(|(path, hash)| hash_file(path).as_deref() == Some(hash.as_str()))
+    }
+}
+
+pub(super) fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+pub(super) fn hash_file(path: &Path) -> Option<String> {
+    std::fs::read(path).ok().map(|bytes| hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let root = TempDir::new("test_manifest_roundtrip").unwrap();
+        assert_eq!(Manifest::load(root.path()).unwrap(), Manifest::default());
+
+        let mut manifest = Manifest::default();
+        manifest.outputs.insert(
+            root.path().join("output"),
+            Entry {
+                template: root.path().join("template"),
+                output_hash: hash(b"output"),
+                ..Default::default()
+            },
+        );
+        manifest.save(root.path()).unwrap();
+        assert_eq!(Manifest::load(root.path()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_entry_is_up_to_date() {
+        let root = TempDir::new("test_entry_is_up_to_date").unwrap();
+        let template = root.path().join("template");
+        let include = root.path().join("include");
+        let output = root.path().join("output");
+        std::fs::write(&template, "template").unwrap();
+        std::fs::write(&include, "include").unwrap();
+        std::fs::write(&output, "output").unwrap();
+
+        let entry = Entry {
+            template: template.clone(),
+            template_hash: hash(b"template"),
+            dependencies: BTreeMap::from([(include.clone(), hash(b"include"))]),
+            inputs: Inputs::default(),
+            output_hash: hash(b"output"),
+            original: None,
+        };
+        assert!(entry.is_up_to_date(&template, &Inputs::default(), &output));
+        let inputs = Inputs {
+            config_hash: hash(b"config"),
+            ..Default::default()
+        };
+        assert!(!entry.is_up_to_date(&template, &inputs, &output));
+
+        std::fs::write(&include, "changed").unwrap();
+        assert!(!entry.is_up_to_date(&template, &Inputs::default(), &output));
+    }
+}
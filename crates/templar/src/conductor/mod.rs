@@ -1,69 +1,1514 @@
-use std::{io::Write, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use anyhow::Result;
-use engine::Engine;
+use anyhow::{Context, Result};
+use artifact::{hash_output, Artifact};
+use config::Deploy;
+use engine::{lock_lua, Engine, EngineRegistry, RenderContext, SharedLua};
+use manifest::{Entry, Inputs, Manifest};
+use transaction::Transaction;
+use trebuchet::parser::{split_header, ParserConfig};
 
 use config::Config;
 use config::Rule;
 
+use crate::config::hook::{Hook, HookContext};
+
+mod artifact;
 pub(super) mod config;
 pub(super) mod engine;
+mod exclude;
+mod header;
+mod lua_engine;
+mod manifest;
+mod passthrough;
+mod preview;
+mod transaction;
 pub(super) mod trebuchet;
 
-/*
- * TODO:
- * I probably need some sort of Rule stack that stores the hierarchy or rules
- * that are currently being evaluated, so that things like Include can resolve
- * paths relatively.
- */
+#[derive(Clone, Debug, Default)]
+pub(super) struct ConductorOptions {
+    /* Render everything, but print a preview instead of writing */
+    pub dry_run: bool,
+    /* Number of templates rendered in parallel. 0 means one per CPU */
+    pub jobs: usize,
+    /* Overwrite outputs even if they were modified or not written by templar */
+    pub force: bool,
+    /* Remove the outputs that no rule produces anymore */
+    pub prune: bool,
+}
+
+/* A target of a rule, together with the path it is written to */
+#[derive(Debug)]
+struct Job<'a> {
+    rule: &'a Rule,
+    /* The rules the rule is nested in, outermost first */
+    parents: Vec<&'a Rule>,
+    template: &'a Path,
+    output: PathBuf,
+    /* The rule's, unless the template's header overrides them */
+    syntax: ParserConfig,
+    mode: Option<u32>,
+}
+
+impl Job<'_> {
+    /* Ids of the rule and its parents, e.g. "dotfiles > i3" */
+    fn rule_chain(&self) -> String {
+        self.parents
+            .iter()
+            .chain(std::iter::once(&self.rule))
+            .map(|rule| rule.id.as_str())
+            .collect::<Vec<_>>()
+            .join(" > ")
+    }
+
+    fn render_context(&self, include_dirs: &[PathBuf]) -> RenderContext {
+        RenderContext {
+            id: self.rule.id.clone(),
+            parents: self.parents.iter().map(|rule| rule.id.clone()).collect(),
+            basepath: self.rule.basepath.clone(),
+            template: self.template.to_path_buf(),
+            destination: self.output.clone(),
+            include_dirs: include_dirs.to_vec(),
+            args: self.rule.engine_args.clone(),
+        }
+    }
+
+    /* The rule's mode, or the permission bits of the template */
+    fn mode(&self) -> Result<u32> {
+        match self.mode {
+            Some(mode) => Ok(mode),
+            None => Ok(std::fs::metadata(self.template)?.permissions().mode() & 0o7777),
+        }
+    }
+
+    /* Outputs are rendered again when any of these change */
+    fn inputs(&self, config: &Config) -> Inputs {
+        // Objects are sorted by key, so equal vars serialize the same
+        let vars = serde_json::to_string(&self.rule.engine_args.vars).unwrap_or_default();
+        let syntax = serde_json::to_string(&self.syntax).unwrap_or_default();
+        Inputs {
+            vars_hash: manifest::hash(vars.as_bytes()),
+            config_hash: config.config_hash.clone(),
+            engine: self.rule.engine.clone(),
+            deploy: self.rule.deploy.to_string(),
+            syntax_hash: manifest::hash(syntax.as_bytes()),
+        }
+    }
+
+    fn is_up_to_date(&self, manifest: &Manifest, config: &Config) -> bool {
+        manifest.outputs.get(&self.output).is_some_and(|entry| {
+            entry.is_up_to_date(self.template, &self.inputs(config), &self.output)
+        }) && (is_symlink(&self.output) || current_mode(&self.output) == self.mode().ok())
+    }
+}
+
+fn print_preview(output_path: &Path, artifact: &Artifact) {
+    match artifact {
+        Artifact::File { contents, .. } => {
+            let current = std::fs::read_to_string(output_path).ok();
+            let rendered = String::from_utf8_lossy(contents);
+            preview::print_preview(output_path, current.as_deref(), &rendered);
+        }
+        Artifact::Symlink(target) => {
+            // Anything other than a symlink is shown as the output itself
+            let current = output_path
+                .symlink_metadata()
+                .ok()
+                .map(|_| std::fs::read_link(output_path).unwrap_or_else(|_| output_path.into()));
+            preview::print_symlink_preview(output_path, current.as_deref(), target);
+        }
+    }
+}
+
+/* Outputs in the manifest that are not produced by any job */
+fn orphans(jobs: &[Job], manifest: &Manifest) -> Vec<PathBuf> {
+    let outputs = jobs
+        .iter()
+        .map(|job| job.output.as_path())
+        .collect::<HashSet<_>>();
+    manifest
+        .outputs
+        .keys()
+        .filter(|output| !outputs.contains(output.as_path()))
+        .cloned()
+        .collect()
+}
+
+/* Whether writing the output replaces a file templar did not write */
+fn replaces_unmanaged(output_path: &Path, manifest: &Manifest) -> bool {
+    !manifest.outputs.contains_key(output_path) && output_path.symlink_metadata().is_ok()
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+fn current_mode(path: &Path) -> Option<u32> {
+    std::fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+}
+
+/* Fails if two jobs would write to the same output, as the last one would silently win */
+fn check_collisions(jobs: &[Job]) -> Result<()> {
+    let mut outputs: HashMap<&Path, &Job> = HashMap::new();
+    for job in jobs {
+        if let Some(other) = outputs.insert(&job.output, job) {
+            anyhow::bail!(
+                "Rules {} and {} both write to {:?} (from the templates {:?} and {:?})",
+                other.rule_chain(),
+                job.rule_chain(),
+                job.output,
+                other.template,
+                job.template
+            );
+        }
+    }
+    Ok(())
+}
+
+fn render(engine: &dyn Engine, job: &Job, include_dirs: &[PathBuf]) -> Result<Artifact> {
+    let template_path = job.template;
+    let read_error = || format!("Failed to read the template {:?}", template_path);
+
+    let input = match job.rule.deploy {
+        Deploy::Symlink => return Ok(Artifact::Symlink(template_path.to_path_buf())),
+        Deploy::Copy => {
+            return Ok(Artifact::File {
+                contents: std::fs::read(template_path).with_context(read_error)?,
+                dependencies: vec![],
+            })
+        }
+        // Files that are not even text are certainly not templates
+        Deploy::Auto => match std::fs::read_to_string(template_path) {
+            Ok(input) if engine.is_template(&input) => input,
+            Ok(input) if split_header(&job.rule.syntax, &input).is_some() => input,
+            Ok(_) | Err(_) => return Ok(Artifact::Symlink(template_path.to_path_buf())),
+        },
+        Deploy::Render => std::fs::read_to_string(template_path).with_context(read_error)?,
+    };
+    // The header was already read when planning
+    let input = match split_header(&job.rule.syntax, &input) {
+        Some((_, rest)) => rest,
+        None => input.as_str(),
+    };
+
+    let rendered = engine.run(input, &job.render_context(include_dirs))?;
+    Ok(Artifact::File {
+        contents: rendered.output.into_bytes(),
+        dependencies: rendered.dependencies,
+    })
+}
+
 #[derive(Clone)]
 pub(super) struct Conductor {
-    engine: Box<dyn Engine>,
+    engines: EngineRegistry,
     config: Config,
+    options: ConductorOptions,
+    /*
+     * The Lua state the config was loaded with, which the hooks live in and
+     * templates and headers are evaluated in
+     */
+    lua: Option<SharedLua>,
 }
 
 impl Conductor {
-    pub(super) fn new(engine: Box<dyn Engine>, config: Config) -> Self {
-        Conductor { engine, config }
+    pub(super) fn new(engines: EngineRegistry, config: Config, options: ConductorOptions) -> Self {
+        Conductor {
+            engines,
+            config,
+            options,
+            lua: None,
+        }
     }
 
-    pub(super) fn process_file_at(
+    pub(super) fn with_lua(mut self, lua: SharedLua) -> Self {
+        self.lua = Some(lua);
+        self
+    }
+
+    /* Returns whether the output changes */
+    fn process_file_at(
+        &self,
+        job: &Job,
+        artifact: &Artifact,
+        manifest: &Manifest,
+        transaction: &mut Transaction,
+    ) -> Result<bool> {
+        let template_path = job.template;
+        let output_path = job.output.as_path();
+        if template_path == output_path {
+            anyhow::bail!("Refusing to overwrite the template {:?}", template_path);
+        }
+
+        let overwrite_check = self.check_overwrite(output_path, artifact, manifest);
+
+        if self.options.dry_run {
+            if let Err(e) = overwrite_check {
+                eprintln!("Warning: {}", e);
+            }
+            print_preview(output_path, artifact);
+            return Ok(false);
+        }
+
+        overwrite_check?;
+        let mode = job.mode()?;
+        let is_current = hash_output(output_path) == Some(artifact.hash());
+        let is_current = match artifact {
+            Artifact::File { .. } => is_current && current_mode(output_path) == Some(mode),
+            Artifact::Symlink(_) => is_current,
+        };
+        // Files replaced for the first time are always staged, so that a copy
+        // of them is kept
+        let replaces_unmanaged = replaces_unmanaged(output_path, manifest);
+        if is_current && !replaces_unmanaged {
+            return Ok(false);
+        }
+        transaction.stage(output_path, artifact, mode, job.rule.dir_mode)?;
+        if replaces_unmanaged {
+            transaction.keep_original();
+        }
+        Ok(!is_current)
+    }
+
+    /*
+     * An output can only be overwritten if it is exactly what templar last
+     * wrote, so that changes made by hand are never lost
+     */
+    fn check_overwrite(
         &self,
-        template_path: impl AsRef<Path>,
-        output_path: impl AsRef<Path>,
+        output_path: &Path,
+        artifact: &Artifact,
+        manifest: &Manifest,
     ) -> Result<()> {
-        let input = std::fs::read_to_string(template_path)?;
-        let output = self.engine.run(input.as_str())?;
-        std::fs::File::create(output_path)?.write_all(output.as_bytes())?;
-        Ok(())
+        if self.options.force {
+            return Ok(());
+        }
+        let current_hash = match hash_output(output_path) {
+            Some(current_hash) => current_hash,
+            None => return Ok(()),
+        };
+        if current_hash == artifact.hash() {
+            return Ok(());
+        }
+
+        match manifest.outputs.get(output_path) {
+            Some(entry) if entry.output_hash == current_hash => Ok(()),
+            Some(_) => anyhow::bail!(
+                "{:?} was modified since templar last wrote it. Use --force to overwrite it",
+                output_path
+            ),
+            None => anyhow::bail!(
+                "{:?} already exists and was not written by templar. Use --force to overwrite it",
+                output_path
+            ),
+        }
     }
 
     pub(super) fn conduct(&self) -> Result<()> {
-        /* I need to handle
-         *   - basepaths / relative paths
-         *   - includes
-         */
-        for rule in &self.config.rules {
-            self.process_rule(rule)?;
+        self.conduct_filtered(|_| true)
+    }
+
+    /* Like conduct, but only for the targets of the rules with the given ids */
+    pub(super) fn conduct_rules(&self, rule_ids: &HashSet<String>) -> Result<()> {
+        self.conduct_filtered(|job| rule_ids.contains(&job.rule.id))
+    }
+
+    /*
+     * Ids of the rules that have any of the given paths as a target, or that
+     * included them the last time they were rendered
+     */
+    pub(super) fn rules_affected_by(&self, paths: &[PathBuf]) -> Result<HashSet<String>> {
+        let manifest = Manifest::load(&self.config.dest_base)?;
+        let rule_ids = self
+            .plan()?
+            .into_iter()
+            .filter(|job| {
+                let dependencies = manifest
+                    .outputs
+                    .get(&job.output)
+                    .map(|entry| &entry.dependencies);
+                paths.iter().any(|path| {
+                    path == job.template
+                        || dependencies.is_some_and(|dependencies| dependencies.contains_key(path))
+                })
+            })
+            .map(|job| job.rule.id.clone())
+            .collect();
+        Ok(rule_ids)
+    }
+
+    /* Every file included by a template the last time it was rendered */
+    pub(super) fn dependencies(&self) -> Result<HashSet<PathBuf>> {
+        let manifest = Manifest::load(&self.config.dest_base)?;
+        Ok(manifest
+            .outputs
+            .into_values()
+            .flat_map(|entry| entry.dependencies.into_keys())
+            .collect())
+    }
+
+    pub(super) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn conduct_filtered(&self, filter: impl Fn(&Job) -> bool) -> Result<()> {
+        let jobs = self.plan()?;
+        let mut manifest = Manifest::load(&self.config.dest_base)?;
+
+        // Dry runs preview every target, otherwise only the targets whose
+        // inputs changed since the last run are rendered again
+        let pending = jobs
+            .iter()
+            .filter(|job| filter(job))
+            .filter(|job| self.options.dry_run || !job.is_up_to_date(&manifest, &self.config))
+            .collect::<Vec<_>>();
+
+        // Hooks have side effects, so dry runs skip them
+        if !self.options.dry_run {
+            for job in &pending {
+                self.run_hook("before_render", &job.rule.hooks.before_render, job, None)?;
+            }
+        }
+        let outputs = self.render_all(&pending);
+
+        // Rendering happens out of order, but results are handled in rule
+        // order so that output and errors are deterministic. Nothing is
+        // written unless every template could be rendered
+        let outputs = pending
+            .iter()
+            .zip(outputs)
+            .map(|(job, artifact)| {
+                artifact.with_context(|| {
+                    format!(
+                        "Failed to render the template {:?} of rule {}",
+                        job.template,
+                        job.rule_chain()
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut transaction = Transaction::new(&self.config.dest_base);
+        let changed = pending
+            .iter()
+            .zip(&outputs)
+            .map(|(job, artifact)| self.process_file_at(job, artifact, &manifest, &mut transaction))
+            .collect::<Result<Vec<_>>>()?;
+        if self.options.prune {
+            let orphans = orphans(&jobs, &manifest);
+            self.retire(&orphans, &mut manifest, &mut transaction)?;
+        }
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        for (job, artifact) in pending.iter().zip(&outputs) {
+            let original = match manifest.outputs.get(&job.output) {
+                Some(entry) => entry.original.clone(),
+                None if replaces_unmanaged(&job.output, &manifest) => Some(
+                    transaction::original_path(&self.config.dest_base, &job.output),
+                ),
+                None => None,
+            };
+            let entry = Entry {
+                original,
+                ..Entry::new(job.template, artifact, job.inputs(&self.config))
+            };
+            manifest.outputs.insert(job.output.clone(), entry);
+        }
+        transaction.commit(&manifest)?;
+
+        for (job, changed) in pending.iter().zip(changed) {
+            let hooks = &job.rule.hooks;
+            self.run_hook("after_write", &hooks.after_write, job, Some(changed))?;
+            if changed {
+                self.run_hook("on_change", &hooks.on_change, job, Some(changed))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_hook(
+        &self,
+        name: &str,
+        hook: &Option<Hook>,
+        job: &Job,
+        changed: Option<bool>,
+    ) -> Result<()> {
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        let lua = self
+            .lua
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No Lua state to run hooks in"))?;
+        let context = HookContext {
+            rule: &job.rule.id,
+            template: job.template,
+            output: &job.output,
+            changed,
+        };
+        hook.call(&*lock_lua(lua)?, &context).with_context(|| {
+            format!(
+                "Failed to run the {} hook of rule {} for {:?}",
+                name,
+                job.rule_chain(),
+                job.template
+            )
+        })
+    }
+
+    /*
+     * Removes the outputs that were written by templar but that no rule
+     * produces anymore, e.g. because their template was deleted. confirm is
+     * given the orphaned outputs and can still cancel the removal.
+     */
+    pub(super) fn clean(&self, confirm: impl FnOnce(&[PathBuf]) -> Result<bool>) -> Result<()> {
+        let jobs = self.plan()?;
+        let manifest = Manifest::load(&self.config.dest_base)?;
+        let orphans = orphans(&jobs, &manifest);
+        if orphans.is_empty() {
+            println!("Nothing to clean");
+            return Ok(());
+        }
+        self.retire_confirmed(&orphans, manifest, confirm)
+    }
+
+    /*
+     * Removes every output templar manages, putting back the files they
+     * replaced when they were first written
+     */
+    pub(super) fn uninstall(&self, confirm: impl FnOnce(&[PathBuf]) -> Result<bool>) -> Result<()> {
+        let manifest = Manifest::load(&self.config.dest_base)?;
+        let outputs = manifest.outputs.keys().cloned().collect::<Vec<_>>();
+        if outputs.is_empty() {
+            println!("Nothing to uninstall");
+            return Ok(());
+        }
+        self.retire_confirmed(&outputs, manifest, confirm)
+    }
+
+    fn retire_confirmed(
+        &self,
+        outputs: &[PathBuf],
+        mut manifest: Manifest,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+    ) -> Result<()> {
+        if !confirm(outputs)? {
+            return Ok(());
+        }
+
+        let mut transaction = Transaction::new(&self.config.dest_base);
+        let retired = self.retire(outputs, &mut manifest, &mut transaction)?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+        transaction.commit(&manifest)?;
+        for (output, restored) in retired {
+            if restored {
+                println!("Restored the original {}", output.display());
+            } else {
+                println!("Removed {}", output.display());
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Stages putting back the originals of outputs, or removing the outputs
+     * that did not replace anything, and forgets them in the manifest.
+     * Outputs modified by hand are left alone unless forced. Returns the
+     * outputs that will be retired, and whether their original is restored.
+     */
+    fn retire(
+        &self,
+        outputs: &[PathBuf],
+        manifest: &mut Manifest,
+        transaction: &mut Transaction,
+    ) -> Result<Vec<(PathBuf, bool)>> {
+        let mut retired = Vec::new();
+        for output_path in outputs {
+            let current_hash = hash_output(output_path);
+            let entry = manifest.outputs.get(output_path);
+            let is_modified = current_hash.is_some()
+                && entry.map(|entry| &entry.output_hash) != current_hash.as_ref();
+            if is_modified && !self.options.force {
+                eprintln!(
+                    "Warning: {:?} was modified since templar last wrote it. Use --force to remove it",
+                    output_path
+                );
+                continue;
+            }
+
+            let original = manifest
+                .outputs
+                .remove(output_path)
+                .and_then(|entry| entry.original)
+                .filter(|original| original.symlink_metadata().is_ok());
+            match &original {
+                Some(original) if self.options.dry_run => {
+                    preview::print_restore_preview(output_path, original)
+                }
+                Some(original) => transaction.stage_copy(output_path, original)?,
+                None if current_hash.is_none() => continue,
+                None if self.options.dry_run => preview::print_removal_preview(output_path),
+                None => transaction.stage_removal(output_path),
+            }
+            retired.push((output_path.clone(), original.is_some()));
+        }
+        Ok(retired)
+    }
+
+    /* Rolls back the outputs written by the last run */
+    pub(super) fn rollback(&self) -> Result<()> {
+        match transaction::rollback(&self.config.dest_base)? {
+            Some(outputs) => {
+                for output in outputs {
+                    println!("Restored {}", output.display());
+                }
+            }
+            None => println!("Nothing to roll back"),
         }
         Ok(())
     }
 
-    fn process_rule(&self, rule: &Rule) -> Result<()> {
-        for rule in &rule.rules {
-            self.process_rule(rule)?;
+    /* Lists every target of every rule, children first */
+    fn plan(&self) -> Result<Vec<Job<'_>>> {
+        let mut jobs = Vec::new();
+        let lua = self.shared_lua();
+        for rule in &self.config.rules {
+            self.plan_rule(rule, &[], &lua, &mut jobs)?;
+        }
+        for job in &jobs {
+            if !self.engines.contains(&job.rule.engine) {
+                // Fails with the list of known engines
+                self.engines
+                    .create(&job.rule.engine, ParserConfig::default(), lua.clone())
+                    .with_context(|| format!("Invalid rule {}", job.rule_chain()))?;
+            }
+        }
+        check_collisions(&jobs)?;
+        Ok(jobs)
+    }
+
+    fn plan_rule<'a>(
+        &self,
+        rule: &'a Rule,
+        parents: &[&'a Rule],
+        lua: &SharedLua,
+        jobs: &mut Vec<Job<'a>>,
+    ) -> Result<()> {
+        let mut stack = parents.to_vec();
+        stack.push(rule);
+        for child in &rule.rules {
+            self.plan_rule(child, &stack, lua, jobs)?;
         }
 
         for target in &rule.targets {
-            //self.process_file_at(target, self.config.dest_base.join(target))?;
-            println!(
-                "self.process_file_at({:?}, {:?})?",
-                target,
-                self.config.dest_base.join(target)
-            );
-            // TODO!
+            let mut job = Job {
+                rule,
+                parents: parents.to_vec(),
+                template: target,
+                output: self.output_path(rule, target, lua)?,
+                syntax: rule.syntax.clone(),
+                mode: rule.mode,
+            };
+            let enabled = self.apply_header(&mut job, lua).with_context(|| {
+                format!(
+                    "Invalid header in {:?} of rule {}",
+                    target,
+                    job.rule_chain()
+                )
+            })?;
+            if enabled {
+                jobs.push(job);
+            }
         }
 
         Ok(())
     }
+
+    /*
+     * Applies the settings in the header of a job's template, if it has one.
+     * Returns whether the template is enabled.
+     */
+    fn apply_header(&self, job: &mut Job, lua: &SharedLua) -> Result<bool> {
+        if !matches!(job.rule.deploy, Deploy::Render | Deploy::Auto) {
+            return Ok(true);
+        }
+        // Templates that can't be read fail once they are rendered
+        let input = match std::fs::read_to_string(job.template) {
+            Ok(input) => input,
+            Err(_) => return Ok(true),
+        };
+        let source = match split_header(&job.rule.syntax, &input) {
+            Some((source, _)) => source,
+            None => return Ok(true),
+        };
+
+        let context = job.render_context(&self.config.include_dirs);
+        let header = header::eval_header(&*lock_lua(lua)?, source, &context)?;
+        if !header.enabled {
+            return Ok(false);
+        }
+        job.syntax = config::apply_syntax(&header.syntax, &job.syntax)?;
+        if let Some(mode) = header.mode {
+            job.mode = Some(config::parse_mode(&mode)?);
+        }
+        if let Some(destination) = header.destination {
+            let destination = config::expand_home(destination)?;
+            job.output = self.config.dest_base.join(destination);
+        }
+        Ok(true)
+    }
+
+    /* The engine of a job with its syntax, created the first time it is needed */
+    fn engine<'a>(
+        &self,
+        engines: &'a mut HashMap<(String, ParserConfig), Box<dyn Engine>>,
+        job: &Job,
+        lua: &SharedLua,
+    ) -> Result<&'a dyn Engine> {
+        let key = (job.rule.engine.clone(), job.syntax.clone());
+        if !engines.contains_key(&key) {
+            let engine = self
+                .engines
+                .create(&job.rule.engine, job.syntax.clone(), lua.clone())?;
+            engines.insert(key.clone(), engine);
+        }
+        Ok(&*engines[&key])
+    }
+
+    /*
+     * The config's Lua state, or a new one when there is no config. Templates
+     * evaluated in the config's take turns, other states render in parallel
+     */
+    fn shared_lua(&self) -> SharedLua {
+        self.lua.clone().unwrap_or_default()
+    }
+
+    /*
+     * Renders every job on a pool of workers. Each worker creates its own
+     * engines as rules need them. The results are in the same order as the
+     * jobs.
+     */
+    fn render_all(&self, jobs: &[&Job]) -> Vec<Result<Artifact>> {
+        let workers = self.workers().min(jobs.len());
+        let next_job = AtomicUsize::new(0);
+
+        let mut outputs = std::iter::repeat_with(|| None)
+            .take(jobs.len())
+            .collect::<Vec<_>>();
+        std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    let next_job = &next_job;
+                    let include_dirs = &self.config.include_dirs;
+                    scope.spawn(move || {
+                        let lua = self.shared_lua();
+                        let mut engines = HashMap::new();
+                        let mut rendered = Vec::new();
+                        loop {
+                            let i = next_job.fetch_add(1, Ordering::Relaxed);
+                            let job = match jobs.get(i) {
+                                Some(job) => job,
+                                None => break,
+                            };
+                            let output = self
+                                .engine(&mut engines, job, &lua)
+                                .and_then(|engine| render(engine, job, include_dirs));
+                            rendered.push((i, output));
+                        }
+                        rendered
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for handle in handles {
+                let rendered = handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                for (i, output) in rendered {
+                    outputs[i] = Some(output);
+                }
+            }
+        });
+
+        outputs
+            .into_iter()
+            .map(|output| output.expect("Every job should have been rendered"))
+            .collect()
+    }
+
+    fn workers(&self) -> usize {
+        match self.options.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        }
+    }
+
+    /*
+     * Mirrors the target's path relative to the rule's basepath under its dest,
+     * or dest_base, after renaming it
+     */
+    fn output_path(&self, rule: &Rule, target: &Path, lua: &SharedLua) -> Result<PathBuf> {
+        let mut relative_path = target
+            .strip_prefix(&rule.basepath)
+            .with_context(|| {
+                format!(
+                    "Target {:?} is not inside the basepath {:?} of rule {}",
+                    target, rule.basepath, rule.id
+                )
+            })?
+            .to_path_buf();
+        if !rule.renames.is_empty() {
+            let lua = lock_lua(lua)?;
+            for rename in &rule.renames {
+                relative_path = rename.apply(&relative_path, &lua)?;
+            }
+        }
+        let dest = match &rule.dest {
+            Some(dest) => self.config.dest_base.join(dest),
+            None => self.config.dest_base.clone(),
+        };
+        Ok(dest.join(relative_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{rawconfig::EngineArgs, rawrule::RawRule};
+    use indoc::indoc;
+    use rlua::Lua;
+    use std::fs::File;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    fn config(rules: Vec<Rule>, dest_base: &Path) -> Config {
+        Config {
+            rules,
+            dest_base: dest_base.to_path_buf(),
+            include_dirs: vec![],
+            engine_args: EngineArgs::default(),
+            config_hash: String::new(),
+        }
+    }
+
+    fn conduct(config: Config, options: ConductorOptions) -> Result<()> {
+        Conductor::new(EngineRegistry::default(), config, options).conduct()
+    }
+
+    #[test]
+    fn test_conduct() {
+        let root = TempDir::new("test_conduct").unwrap();
+        let basepath = root.path().join("templates");
+        let dest_base = root.path().join("dest");
+
+        std::fs::create_dir_all(basepath.join("nested")).unwrap();
+        let basepath = basepath.canonicalize().unwrap();
+        let template = basepath.join("nested/file.conf");
+        std::fs::write(&template, "!!% if true %!!\ntext\n!!% end %!!\n").unwrap();
+
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+
+        let options = ConductorOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        conduct(config.clone(), options).unwrap();
+        assert!(!dest_base.exists());
+
+        conduct(config, ConductorOptions::default()).unwrap();
+        let output_path = dest_base.join("nested/file.conf");
+        let output = std::fs::read_to_string(&output_path).unwrap();
+        assert_eq!(output, "text\n");
+
+        let manifest = Manifest::load(&dest_base).unwrap();
+        assert_eq!(manifest.outputs[&output_path].template, template);
+    }
+
+    #[test]
+    fn test_rules_affected_by() {
+        let root = TempDir::new("test_rules_affected_by").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let including = basepath.join("including.conf");
+        let plain = basepath.join("plain.conf");
+        let part = basepath.join("part.inc");
+        std::fs::write(&including, "!!% include part.inc %!!").unwrap();
+        std::fs::write(&plain, "plain").unwrap();
+        std::fs::write(&part, "part").unwrap();
+
+        let rule = |id: &str, target: &Path| Rule {
+            id: id.to_string(),
+            targets: vec![target.to_path_buf()],
+            basepath: basepath.clone(),
+            ..Default::default()
+        };
+        let conductor = Conductor::new(
+            EngineRegistry::default(),
+            config(
+                vec![rule("including", &including), rule("plain", &plain)],
+                &dest_base,
+            ),
+            ConductorOptions::default(),
+        );
+        conductor.conduct().unwrap();
+        assert_eq!(
+            conductor.dependencies().unwrap(),
+            HashSet::from([part.clone()])
+        );
+
+        let affected = |path: &Path| conductor.rules_affected_by(&[path.to_path_buf()]).unwrap();
+        // A template, and a file a template includes
+        assert_eq!(affected(&plain), HashSet::from(["plain".to_string()]));
+        assert_eq!(affected(&part), HashSet::from(["including".to_string()]));
+        assert!(affected(&basepath.join("other.conf")).is_empty());
+    }
+
+    #[test]
+    fn test_conduct_collisions() {
+        let root = TempDir::new("test_conduct_collisions").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("file.conf");
+        File::create(&template).unwrap();
+
+        let rule = |id: &str| Rule {
+            id: id.to_string(),
+            targets: vec![template.clone()],
+            basepath: basepath.clone(),
+            ..Default::default()
+        };
+        let config = config(vec![rule("first"), rule("second")], &dest_base);
+        let err = conduct(config, ConductorOptions::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("first") && err.contains("second"), "{}", err);
+        assert!(!dest_base.exists());
+    }
+
+    #[test]
+    fn test_conduct_refuses_to_overwrite() {
+        let root = TempDir::new("test_conduct_refuses_to_overwrite").unwrap();
+        let basepath = root.path().join("templates");
+        let dest_base = root.path().join("dest");
+        std::fs::create_dir_all(&basepath).unwrap();
+        std::fs::create_dir_all(&dest_base).unwrap();
+        let basepath = basepath.canonicalize().unwrap();
+        let template = basepath.join("file.conf");
+        let output = dest_base.join("file.conf");
+        std::fs::write(&template, "rendered\n").unwrap();
+        std::fs::write(&output, "unmanaged\n").unwrap();
+
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+        let conduct = |force: bool| {
+            let options = ConductorOptions {
+                force,
+                ..Default::default()
+            };
+            conduct(config.clone(), options)
+        };
+
+        // Never written by templar
+        assert!(conduct(false).is_err());
+        conduct(true).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "rendered\n");
+
+        // Edited by hand since the last run
+        std::fs::write(&output, "edited\n").unwrap();
+        std::fs::write(&template, "rendered again\n").unwrap();
+        assert!(conduct(false).is_err());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "edited\n");
+
+        std::fs::write(&output, "rendered\n").unwrap();
+        conduct(false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "rendered again\n"
+        );
+    }
+
+    #[test]
+    fn test_conduct_failure_writes_nothing() {
+        let root = TempDir::new("test_conduct_failure_writes_nothing").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let valid = basepath.join("valid.conf");
+        let invalid = basepath.join("invalid.conf");
+        std::fs::write(&valid, "text\n").unwrap();
+        std::fs::write(&invalid, "!!% if not valid lua %!!\ntext\n!!% end %!!\n").unwrap();
+
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![valid, invalid],
+                basepath,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+        let result = conduct(config, ConductorOptions::default());
+        assert!(result.is_err());
+        assert!(!dest_base.join("valid.conf").exists());
+    }
+
+    #[test]
+    fn test_conduct_auto_deploy() {
+        let root = TempDir::new("test_conduct_auto_deploy").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let plain = basepath.join("plain.conf");
+        let template = basepath.join("template.conf");
+        std::fs::write(&plain, "plain\n").unwrap();
+        std::fs::write(&template, "!!% if true %!!\ntext\n!!% end %!!\n").unwrap();
+
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![plain.clone(), template],
+                basepath,
+                deploy: Deploy::Auto,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+        let conductor = Conductor::new(
+            EngineRegistry::default(),
+            config,
+            ConductorOptions::default(),
+        );
+        conductor.conduct().unwrap();
+        assert_eq!(
+            std::fs::read_link(dest_base.join("plain.conf")).unwrap(),
+            plain
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest_base.join("template.conf")).unwrap(),
+            "text\n"
+        );
+
+        // Symlinks are up to date as long as they point to the template
+        conductor.conduct().unwrap();
+        assert_eq!(
+            std::fs::read_link(dest_base.join("plain.conf")).unwrap(),
+            plain
+        );
+    }
+
+    #[test]
+    fn test_conduct_deploy_changes() {
+        let root = TempDir::new("test_conduct_deploy_changes").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("file.conf");
+        std::fs::write(&template, "text\n").unwrap();
+
+        let conduct = |deploy: Deploy| {
+            let rule = Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath: basepath.clone(),
+                deploy,
+                ..Default::default()
+            };
+            conduct(config(vec![rule], &dest_base), ConductorOptions::default()).unwrap();
+            is_symlink(&dest_base.join("file.conf"))
+        };
+        assert!(!conduct(Deploy::Render));
+        assert!(conduct(Deploy::Symlink));
+        assert!(!conduct(Deploy::Render));
+    }
+
+    #[test]
+    fn test_conduct_syntax_changes() {
+        let root = TempDir::new("test_conduct_syntax_changes").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("file.conf");
+        std::fs::write(&template, "{{ if false }}text{{ end }}").unwrap();
+
+        let conduct = |syntax: ParserConfig| {
+            let rule = Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath: basepath.clone(),
+                syntax,
+                ..Default::default()
+            };
+            conduct(config(vec![rule], &dest_base), ConductorOptions::default()).unwrap();
+            std::fs::read_to_string(dest_base.join("file.conf")).unwrap()
+        };
+        let syntax = |odelim: &str, cdelim: &str| ParserConfig {
+            odelim: odelim.to_string(),
+            cdelim: cdelim.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(conduct(syntax("<<", ">>")), "{{ if false }}text{{ end }}");
+        assert_eq!(conduct(syntax("{{", "}}")), "");
+    }
+
+    #[test]
+    fn test_conduct_prune() {
+        let root = TempDir::new("test_conduct_prune").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let kept = basepath.join("kept.conf");
+        let deleted = basepath.join("deleted.conf");
+        let edited = basepath.join("edited.conf");
+        for template in [&kept, &deleted, &edited] {
+            std::fs::write(template, "text\n").unwrap();
+        }
+
+        let conduct = |targets: Vec<PathBuf>, prune: bool| {
+            let config = config(
+                vec![Rule {
+                    id: "rule".to_string(),
+                    targets,
+                    basepath: basepath.clone(),
+                    ..Default::default()
+                }],
+                &dest_base,
+            );
+            let options = ConductorOptions {
+                prune,
+                ..Default::default()
+            };
+            conduct(config, options)
+        };
+        conduct(vec![kept.clone(), deleted.clone(), edited.clone()], false).unwrap();
+        std::fs::write(dest_base.join("edited.conf"), "edited\n").unwrap();
+
+        // Orphans are only removed when pruning
+        conduct(vec![kept.clone()], false).unwrap();
+        assert!(dest_base.join("deleted.conf").exists());
+
+        conduct(vec![kept], true).unwrap();
+        assert!(dest_base.join("kept.conf").exists());
+        assert!(!dest_base.join("deleted.conf").exists());
+        assert!(dest_base.join("edited.conf").exists());
+        let manifest = Manifest::load(&dest_base).unwrap();
+        assert!(!manifest
+            .outputs
+            .contains_key(&dest_base.join("deleted.conf")));
+        assert!(manifest
+            .outputs
+            .contains_key(&dest_base.join("edited.conf")));
+    }
+
+    #[test]
+    fn test_conduct_uninstall() {
+        let root = TempDir::new("test_conduct_uninstall").unwrap();
+        let basepath = root.path().join("templates");
+        let dest_base = root.path().join("dest");
+        std::fs::create_dir_all(&basepath).unwrap();
+        std::fs::create_dir_all(&dest_base).unwrap();
+        let basepath = basepath.canonicalize().unwrap();
+        let replacing = basepath.join("replacing.conf");
+        let creating = basepath.join("creating.conf");
+        std::fs::write(&replacing, "rendered\n").unwrap();
+        std::fs::write(&creating, "rendered\n").unwrap();
+        std::fs::write(dest_base.join("replacing.conf"), "original\n").unwrap();
+
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![replacing, creating],
+                basepath,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+        let options = ConductorOptions {
+            force: true,
+            ..Default::default()
+        };
+        let conductor = Conductor::new(EngineRegistry::default(), config, options);
+        conductor.conduct().unwrap();
+        // The original is kept across runs
+        conductor.conduct().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest_base.join("replacing.conf")).unwrap(),
+            "rendered\n"
+        );
+
+        conductor.uninstall(|_| Ok(true)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest_base.join("replacing.conf")).unwrap(),
+            "original\n"
+        );
+        assert!(!dest_base.join("creating.conf").exists());
+        assert!(Manifest::load(&dest_base).unwrap().outputs.is_empty());
+    }
+
+    #[test]
+    fn test_conduct_hooks() {
+        let root = TempDir::new("test_conduct_hooks").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("file.conf");
+        std::fs::write(&template, "text\n").unwrap();
+
+        let lua = Lua::new();
+        let rule = indoc!(
+            r#"
+            {
+                targets = "*",
+                after_write = function(ctx) writes = (writes or 0) + 1 end,
+                on_change = function(ctx) changes = (changes or 0) + 1 end,
+            }
+            "#
+        );
+        let hooks = lua
+            .context(|lua_context| lua_context.load(rule).eval::<RawRule>())
+            .unwrap()
+            .hooks;
+        let config = config(
+            vec![Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath,
+                hooks,
+                ..Default::default()
+            }],
+            &dest_base,
+        );
+        let lua = Arc::new(Mutex::new(lua));
+        let conductor = Conductor::new(
+            EngineRegistry::default(),
+            config,
+            ConductorOptions::default(),
+        )
+        .with_lua(lua.clone());
+        let counts = || {
+            let lua = lua.lock().unwrap();
+            lua.context(|lua_context| -> rlua::Result<(u32, u32)> {
+                let globals = lua_context.globals();
+                Ok((globals.get("writes")?, globals.get("changes")?))
+            })
+            .unwrap()
+        };
+
+        conductor.conduct().unwrap();
+        assert_eq!(counts(), (1, 1));
+
+        // Rendered again, as if the template changed, but to the same output
+        let mut manifest = Manifest::load(&dest_base).unwrap();
+        manifest
+            .outputs
+            .get_mut(&dest_base.join("file.conf"))
+            .unwrap()
+            .template_hash = String::new();
+        manifest.save(&dest_base).unwrap();
+        conductor.conduct().unwrap();
+        assert_eq!(counts(), (2, 1));
+    }
+
+    #[test]
+    fn test_conduct_engines() {
+        let root = TempDir::new("test_conduct_engines").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let copied = basepath.join("copied.conf");
+        let script = basepath.join("script.lua");
+        let template = "!!% if false %!!text!!% end %!!";
+        std::fs::write(&copied, template).unwrap();
+        std::fs::write(&script, "return templar.current.id").unwrap();
+
+        let rule = |id: &str, target: &Path, engine: &str| Rule {
+            id: id.to_string(),
+            targets: vec![target.to_path_buf()],
+            basepath: basepath.clone(),
+            engine: engine.to_string(),
+            ..Default::default()
+        };
+        let conduct = |rules: Vec<Rule>| {
+            let config = config(rules, &dest_base);
+            conduct(config, ConductorOptions::default())
+        };
+
+        // Switching engines renders the unchanged template again
+        conduct(vec![rule("copied", &copied, "trebuchet")]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest_base.join("copied.conf")).unwrap(),
+            ""
+        );
+        conduct(vec![
+            rule("copied", &copied, "copy"),
+            rule("script", &script, "lua"),
+        ])
+        .unwrap();
+        let output = |name: &str| std::fs::read_to_string(dest_base.join(name)).unwrap();
+        assert_eq!(output("copied.conf"), template);
+        assert_eq!(output("script.lua"), "script");
+
+        let err = conduct(vec![rule("unknown", &copied, "unknown")]).unwrap_err();
+        assert!(format!("{:?}", err).contains("Unknown engine"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_conduct_header() {
+        let root = TempDir::new("test_conduct_header").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("template.conf");
+        let disabled = basepath.join("disabled.conf");
+        std::fs::write(
+            &template,
+            indoc!(
+                r#"
+                !!% ## {
+                  odelim = "<%", cdelim = "%>",
+                  destination = "dir/renamed.conf", mode = "600",
+                } %!!
+                <% if true %>text<% end %>
+                "#
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            &disabled,
+            "!!% ## { enabled = function() return templar.current.id ~= \"rule\" end } %!!\n",
+        )
+        .unwrap();
+
+        let rule = |targets: Vec<PathBuf>| Rule {
+            id: "rule".to_string(),
+            targets,
+            basepath: basepath.clone(),
+            ..Default::default()
+        };
+        conduct(
+            config(vec![rule(vec![template, disabled])], &dest_base),
+            ConductorOptions::default(),
+        )
+        .unwrap();
+
+        let output = dest_base.join("dir/renamed.conf");
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "text");
+        assert_eq!(current_mode(&output).unwrap() & 0o777, 0o600);
+        assert!(!dest_base.join("template.conf").exists());
+        assert!(!dest_base.join("disabled.conf").exists());
+
+        let invalid = basepath.join("invalid.conf");
+        std::fs::write(&invalid, "!!% ## { mode = \"999\" } %!!\n").unwrap();
+        let error = conduct(
+            config(vec![rule(vec![invalid.clone()])], &dest_base),
+            ConductorOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "Invalid header in {:?} of rule rule: \
+                 Invalid mode \"999\", expected octal permission bits",
+                invalid
+            )
+        );
+    }
+
+    #[test]
+    fn test_conduct_vars() {
+        let root = TempDir::new("test_conduct_vars").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("template.conf");
+        std::fs::write(
+            &template,
+            "!!% if vars.user == \"me\" %!!mine!!% else %!!theirs!!% end %!!",
+        )
+        .unwrap();
+
+        let conduct = |user: &str| {
+            let mut engine_args = EngineArgs::default();
+            engine_args.vars.insert("user".to_string(), user.into());
+            let config = config(
+                vec![Rule {
+                    id: "rule".to_string(),
+                    targets: vec![template.clone()],
+                    basepath: basepath.clone(),
+                    engine_args,
+                    ..Default::default()
+                }],
+                &dest_base,
+            );
+            conduct(config, ConductorOptions::default()).unwrap();
+            std::fs::read_to_string(dest_base.join("template.conf")).unwrap()
+        };
+
+        assert_eq!(conduct("me"), "mine");
+        // The template didn't change, but its vars did
+        assert_eq!(conduct("you"), "theirs");
+    }
+
+    #[test]
+    fn test_conduct_shared_lua() {
+        let root = TempDir::new("test_conduct_shared_lua").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("template.conf");
+        std::fs::write(
+            &template,
+            "!!% transform input %!! shout(input) !!% to %!!text!!% end %!!",
+        )
+        .unwrap();
+        let script = basepath.join("script.lua");
+        std::fs::write(&script, "leaked = true; return shout(vars.user)").unwrap();
+
+        let lua = Lua::new();
+        lua.context(|lua_context| {
+            lua_context
+                .load(r#"function shout(s) return s:upper() end; vars = "config's""#)
+                .exec()
+        })
+        .unwrap();
+        let lua = Arc::new(Mutex::new(lua));
+
+        let mut engine_args = EngineArgs::default();
+        engine_args.vars.insert("user".to_string(), "me".into());
+        let rule = |target: &Path, engine: &str| Rule {
+            id: engine.to_string(),
+            targets: vec![target.to_path_buf()],
+            basepath: basepath.clone(),
+            engine: engine.to_string(),
+            engine_args: engine_args.clone(),
+            ..Default::default()
+        };
+        let config = config(
+            vec![rule(&template, "trebuchet"), rule(&script, "lua")],
+            &dest_base,
+        );
+        // Both workers render in the config's state
+        let options = ConductorOptions {
+            jobs: 2,
+            ..Default::default()
+        };
+        Conductor::new(EngineRegistry::default(), config, options)
+            .with_lua(lua.clone())
+            .conduct()
+            .unwrap();
+
+        let output = |name: &str| std::fs::read_to_string(dest_base.join(name)).unwrap();
+        assert_eq!(output("template.conf"), "TEXT");
+        assert_eq!(output("script.lua"), "ME");
+        // The config's globals are left as they were
+        let lua = lua.lock().unwrap();
+        lua.context(|lua_context| {
+            let globals = lua_context.globals();
+            assert_eq!(globals.get::<_, String>("vars").unwrap(), "config's");
+            assert!(globals.get::<_, Option<bool>>("leaked").unwrap().is_none());
+            assert!(globals.get::<_, Option<bool>>("templar").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_conduct_config_changes() {
+        let root = TempDir::new("test_conduct_config_changes").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        let template = basepath.join("template.conf");
+        std::fs::write(
+            &template,
+            "!!% transform input %!! shout(input) !!% to %!!Text!!% end %!!",
+        )
+        .unwrap();
+
+        let conduct = |shout: &'static str, config_hash: &str| {
+            let lua = Lua::new();
+            lua.context(|lua_context| {
+                lua_context
+                    .load(&format!("function shout(s) return s:{}() end", shout))
+                    .exec()
+            })
+            .unwrap();
+            let rule = Rule {
+                id: "rule".to_string(),
+                targets: vec![template.clone()],
+                basepath: basepath.clone(),
+                ..Default::default()
+            };
+            let config = Config {
+                config_hash: config_hash.to_string(),
+                ..config(vec![rule], &dest_base)
+            };
+            Conductor::new(
+                EngineRegistry::default(),
+                config,
+                ConductorOptions::default(),
+            )
+            .with_lua(Arc::new(Mutex::new(lua)))
+            .conduct()
+            .unwrap();
+            std::fs::read_to_string(dest_base.join("template.conf")).unwrap()
+        };
+
+        assert_eq!(conduct("upper", "config"), "TEXT");
+        // The helpers are only known to change through the config's hash
+        assert_eq!(conduct("lower", "config"), "TEXT");
+        assert_eq!(conduct("lower", "edited config"), "text");
+    }
+
+    #[test]
+    fn test_conduct_renames() {
+        let root = TempDir::new("test_conduct_renames").unwrap();
+        let basepath = root.path().canonicalize().unwrap();
+        let dest_base = basepath.join("dest");
+        std::fs::create_dir_all(basepath.join("dot_config")).unwrap();
+        std::fs::write(basepath.join("dot_config/app.conf.tmpl"), "app").unwrap();
+        std::fs::write(basepath.join("dot_bashrc.tpl"), "bashrc").unwrap();
+
+        let lua = Lua::new();
+        let raw_rule = lua
+            .context(|lua_context| {
+                lua_context
+                    .load(&format!(
+                        r#"{{
+                            id = "rule", targets = "*", rules = {{}}, basepath = {:?},
+                            dest = "home",
+                            rename = {{ "dot_prefix", "strip_tmpl", function(path) return (path:gsub("app", "App")) end }},
+                        }}"#,
+                        basepath
+                    ))
+                    .eval::<RawRule>()
+            })
+            .unwrap();
+        let config = config(vec![Rule::from_raw_rule(raw_rule).unwrap()], &dest_base);
+        Conductor::new(
+            EngineRegistry::default(),
+            config,
+            ConductorOptions::default(),
+        )
+        .with_lua(Arc::new(Mutex::new(lua)))
+        .conduct()
+        .unwrap();
+
+        let output = |name: &str| std::fs::read_to_string(dest_base.join(name)).unwrap();
+        assert_eq!(output("home/.config/App.conf"), "app");
+        assert_eq!(output("home/.bashrc"), "bashrc");
+    }
 }
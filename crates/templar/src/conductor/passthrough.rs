@@ -0,0 +1,25 @@
+use anyhow::Result;
+
+use super::engine::{Engine, RenderContext, Rendered, SharedLua};
+use super::trebuchet::parser::ParserConfig;
+
+/* Engine that outputs its input as is, for files that are not templates */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Passthrough;
+
+impl Engine for Passthrough {
+    fn new(_: ParserConfig, _: SharedLua) -> Self {
+        Passthrough
+    }
+
+    fn run(&self, input: &str, _: &RenderContext) -> Result<Rendered> {
+        Ok(Rendered {
+            output: input.to_string(),
+            dependencies: vec![],
+        })
+    }
+
+    fn is_template(&self, _: &str) -> bool {
+        false
+    }
+}
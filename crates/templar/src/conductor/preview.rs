@@ -0,0 +1,132 @@
+use std::path::Path;
+
+use colored::Colorize;
+use similar::{ChangeTag, TextDiff};
+
+/* What writing a rendered template would do to its destination */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Status {
+    Created,
+    Changed,
+    Unchanged,
+    Removed,
+    Restored,
+}
+
+impl Status {
+    pub(crate) fn of(current: Option<&str>, rendered: &str) -> Self {
+        match current {
+            None => Status::Created,
+            Some(current) if current == rendered => Status::Unchanged,
+            Some(_) => Status::Changed,
+        }
+    }
+}
+
+/* Prints the status of an output and, if it would change, a unified diff */
+pub(super) fn print_preview(output_path: &Path, current: Option<&str>, rendered: &str) {
+    let status = Status::of(current, rendered);
+    println!("{:>6} {}", label(status), output_path.display());
+
+    if status != Status::Unchanged {
+        print!(
+            "{}",
+            colored_diff(output_path, current.unwrap_or(""), rendered)
+        );
+    }
+}
+
+/* Prints the status of an output that is deployed as a symlink */
+pub(super) fn print_symlink_preview(output_path: &Path, current: Option<&Path>, target: &Path) {
+    let status = match current {
+        None => Status::Created,
+        Some(current) if current == target => Status::Unchanged,
+        Some(_) => Status::Changed,
+    };
+    println!(
+        "{:>6} {} -> {}",
+        label(status),
+        output_path.display(),
+        target.display()
+    );
+}
+
+/* Prints an output that would be removed because no rule produces it anymore */
+pub(super) fn print_removal_preview(output_path: &Path) {
+    println!("{:>6} {}", label(Status::Removed), output_path.display());
+}
+
+/* Prints an output that would be replaced by the file it replaced */
+pub(super) fn print_restore_preview(output_path: &Path, original: &Path) {
+    println!(
+        "{:>6} {} from {}",
+        label(Status::Restored),
+        output_path.display(),
+        original.display()
+    );
+}
+
+fn label(status: Status) -> colored::ColoredString {
+    match status {
+        Status::Created => "create".green().bold(),
+        Status::Changed => "change".yellow().bold(),
+        Status::Unchanged => "same".dimmed(),
+        Status::Removed => "remove".red().bold(),
+        Status::Restored => "restore".blue().bold(),
+    }
+}
+
+fn colored_diff(output_path: &Path, current: &str, rendered: &str) -> String {
+    let path = output_path.display().to_string();
+    let diff = TextDiff::from_lines(current, rendered);
+
+    let mut result = format!(
+        "{}\n{}\n",
+        format!("--- {}", path).bold(),
+        format!("+++ {}", path).bold()
+    );
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        result.push_str(&format!("{}\n", hunk.header().to_string().cyan()));
+        for change in hunk.iter_changes() {
+            let line = change.to_string_lossy();
+            let line = line.trim_end_matches('\n');
+            let line = match change.tag() {
+                ChangeTag::Delete => format!("-{}", line).red(),
+                ChangeTag::Insert => format!("+{}", line).green(),
+                ChangeTag::Equal => format!(" {}", line).normal(),
+            };
+            result.push_str(&format!("{}\n", line));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(Status::of(None, "text"), Status::Created);
+        assert_eq!(Status::of(Some("text"), "text"), Status::Unchanged);
+        assert_eq!(Status::of(Some("text"), "other"), Status::Changed);
+    }
+
+    #[test]
+    fn test_colored_diff() {
+        colored::control::set_override(false);
+        let diff = colored_diff(Path::new("out"), "a\nb\nc\n", "a\nB\nc\n");
+        let expected = indoc::indoc!(
+            "
+            --- out
+            +++ out
+            @@ -1,3 +1,3 @@
+             a
+            -b
+            +B
+             c
+            "
+        );
+        assert_eq!(diff, expected);
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    fs::Permissions,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    artifact::Artifact,
+    manifest::{hash, Manifest},
+};
+
+pub(super) const STAGING_DIR_NAME: &str = ".templar-staging";
+pub(super) const BACKUPS_DIR_NAME: &str = ".templar-backups";
+const ORIGINALS_DIR_NAME: &str = "originals";
+const GENERATION_FILE_NAME: &str = "generation.json";
+const MANIFEST_BACKUP_NAME: &str = "manifest";
+/* How many runs can be rolled back. Older generations are removed */
+const KEPT_GENERATIONS: usize = 10;
+
+/*
+ * The outputs written by a single run, with backups of the files they
+ * replaced. Generations are stored under BACKUPS_DIR_NAME, named after the
+ * time they were committed, so that they can be rolled back. Only the last
+ * KEPT_GENERATIONS are kept.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Generation {
+    pub outputs: Vec<GenerationEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct GenerationEntry {
+    pub output: PathBuf,
+    /* File name of the backup inside the generation directory, if the output replaced a file */
+    pub backup: Option<String>,
+    /* Copy of the replaced file kept for uninstalling, see original_path */
+    #[serde(default)]
+    pub original: Option<PathBuf>,
+}
+
+/*
+ * Outputs are first written to a staging area, and only moved into place once
+ * every one of them was rendered. Nothing touches the filesystem before the
+ * first output is staged, and dropping an uncommitted transaction leaves
+ * dest_base as it was.
+ */
+#[derive(Debug)]
+pub(super) struct Transaction {
+    dest_base: PathBuf,
+    staging_dir: PathBuf,
+    staged: Vec<Staged>,
+}
+
+#[derive(Debug)]
+struct Staged {
+    /* None if the output is removed */
+    path: Option<PathBuf>,
+    output: PathBuf,
+    /* Mode of the directories created for the output */
+    dir_mode: Option<u32>,
+    /* Where to keep a copy of the file the output replaces */
+    original: Option<PathBuf>,
+}
+
+impl Transaction {
+    pub(super) fn new(dest_base: &Path) -> Self {
+        Transaction {
+            dest_base: dest_base.to_path_buf(),
+            staging_dir: dest_base.join(STAGING_DIR_NAME),
+            staged: Vec::new(),
+        }
+    }
+
+    /* The mode is ignored for symlinks, as it would apply to the file they point to */
+    pub(super) fn stage(
+        &mut self,
+        output_path: &Path,
+        artifact: &Artifact,
+        mode: u32,
+        dir_mode: Option<u32>,
+    ) -> Result<()> {
+        std::fs::create_dir_all(&self.staging_dir)?;
+        let staged_path = self.staging_dir.join(self.staged.len().to_string());
+        match artifact {
+            Artifact::File { contents, .. } => std::fs::write(&staged_path, contents)
+                .and_then(|_| std::fs::set_permissions(&staged_path, Permissions::from_mode(mode))),
+            Artifact::Symlink(target) => std::os::unix::fs::symlink(target, &staged_path),
+        }
+        .with_context(|| format!("Failed to stage the output {:?}", output_path))?;
+        self.staged.push(Staged {
+            path: Some(staged_path),
+            output: output_path.to_path_buf(),
+            dir_mode,
+            original: None,
+        });
+        Ok(())
+    }
+
+    /* Like stage, but with a copy of an existing file (e.g. an original) */
+    pub(super) fn stage_copy(&mut self, output_path: &Path, from: &Path) -> Result<()> {
+        std::fs::create_dir_all(&self.staging_dir)?;
+        let staged_path = self.staging_dir.join(self.staged.len().to_string());
+        copy(from, &staged_path)
+            .with_context(|| format!("Failed to stage the output {:?}", output_path))?;
+        self.staged.push(Staged {
+            path: Some(staged_path),
+            output: output_path.to_path_buf(),
+            dir_mode: None,
+            original: None,
+        });
+        Ok(())
+    }
+
+    /*
+     * Keeps a copy of the file the last staged output replaces, if any, at
+     * original_path so that it can be restored on uninstall
+     */
+    pub(super) fn keep_original(&mut self) {
+        if let Some(staged) = self.staged.last_mut() {
+            staged.original = Some(original_path(&self.dest_base, &staged.output));
+        }
+    }
+
+    /* Removes the output on commit. It is backed up like any replaced output */
+    pub(super) fn stage_removal(&mut self, output_path: &Path) {
+        self.staged.push(Staged {
+            path: None,
+            output: output_path.to_path_buf(),
+            dir_mode: None,
+            original: None,
+        });
+    }
+
+    /*
+     * Moves every staged output into place, backing up the files they replace,
+     * and saves the manifest. If anything fails halfway, the outputs that were
+     * already replaced are restored.
+     */
+    pub(super) fn commit(mut self, manifest: &Manifest) -> Result<()> {
+        if self.staged.is_empty() {
+            return manifest.save(&self.dest_base);
+        }
+
+        let generation_dir = create_generation_dir(&self.dest_base)?;
+
+        let mut generation = Generation::default();
+        let result = self.swap_all(&generation_dir, &mut generation, manifest);
+        let result = result.and_then(|_| save_generation(&generation_dir, &generation));
+        if let Err(e) = result {
+            restore(&generation_dir, &generation)?;
+            std::fs::remove_dir_all(&generation_dir)?;
+            return Err(e);
+        }
+
+        self.staged.clear();
+        // The run already succeeded, so failing to clean up is not an error
+        if let Err(e) = prune_generations(&self.dest_base) {
+            eprintln!("Warning: Failed to remove old backups: {:?}", e);
+        }
+        Ok(())
+    }
+
+    fn swap_all(
+        &self,
+        generation_dir: &Path,
+        generation: &mut Generation,
+        manifest: &Manifest,
+    ) -> Result<()> {
+        let manifest_path = Manifest::path(&self.dest_base);
+        if manifest_path.exists() {
+            copy(&manifest_path, &generation_dir.join(MANIFEST_BACKUP_NAME))?;
+        }
+
+        for (i, staged) in self.staged.iter().enumerate() {
+            let output_path = &staged.output;
+            let backup = if output_path.symlink_metadata().is_ok() {
+                let backup = i.to_string();
+                copy(output_path, &generation_dir.join(&backup))
+                    .with_context(|| format!("Failed to back up {:?}", output_path))?;
+                Some(backup)
+            } else {
+                None
+            };
+            let original = match (&staged.original, &backup) {
+                (Some(original), Some(backup)) => {
+                    std::fs::create_dir_all(
+                        self.dest_base
+                            .join(BACKUPS_DIR_NAME)
+                            .join(ORIGINALS_DIR_NAME),
+                    )?;
+                    let _ = std::fs::remove_file(original);
+                    copy(&generation_dir.join(backup), original).with_context(|| {
+                        format!("Failed to keep the original of {:?}", output_path)
+                    })?;
+                    Some(original.clone())
+                }
+                _ => None,
+            };
+
+            match &staged.path {
+                Some(staged_path) => {
+                    if let Some(parent) = output_path.parent() {
+                        create_dir_all(parent, staged.dir_mode)?;
+                    }
+                    move_into_place(staged_path, output_path)
+                        .with_context(|| format!("Failed to write the output {:?}", output_path))?;
+                }
+                None if backup.is_some() => std::fs::remove_file(output_path)
+                    .with_context(|| format!("Failed to remove the output {:?}", output_path))?,
+                None => continue,
+            }
+            generation.outputs.push(GenerationEntry {
+                output: output_path.clone(),
+                backup,
+                original,
+            });
+        }
+
+        manifest.save(&self.dest_base)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // Best effort, the staging area is only left behind if this fails
+        let _ = std::fs::remove_dir_all(&self.staging_dir);
+    }
+}
+
+/*
+ * Undoes the last committed generation: restores the files it replaced,
+ * removes the ones it created and restores the manifest. Returns the outputs
+ * that were rolled back, or None if there is nothing to roll back.
+ */
+pub(super) fn rollback(dest_base: &Path) -> Result<Option<Vec<PathBuf>>> {
+    let generation_dir = match last_generation_dir(dest_base)? {
+        Some(generation_dir) => generation_dir,
+        None => return Ok(None),
+    };
+    let path = generation_dir.join(GENERATION_FILE_NAME);
+    let generation: Generation = serde_json::from_str(&std::fs::read_to_string(&path)?)
+        .with_context(|| format!("Failed to parse the generation {:?}", path))?;
+
+    restore(&generation_dir, &generation)?;
+
+    let manifest_path = Manifest::path(dest_base);
+    let manifest_backup = generation_dir.join(MANIFEST_BACKUP_NAME);
+    if manifest_backup.exists() {
+        move_into_place(&manifest_backup, &manifest_path)?;
+    } else if manifest_path.exists() {
+        std::fs::remove_file(&manifest_path)?;
+    }
+
+    std::fs::remove_dir_all(&generation_dir)?;
+    Ok(Some(
+        generation
+            .outputs
+            .into_iter()
+            .map(|entry| entry.output)
+            .collect(),
+    ))
+}
+
+/* Puts back the files replaced by a generation, in reverse order */
+fn restore(generation_dir: &Path, generation: &Generation) -> Result<()> {
+    for entry in generation.outputs.iter().rev() {
+        if let Some(original) = &entry.original {
+            // The restored manifest does not refer to it anymore
+            let _ = std::fs::remove_file(original);
+        }
+        match &entry.backup {
+            Some(backup) => move_into_place(&generation_dir.join(backup), &entry.output),
+            None => match std::fs::remove_file(&entry.output) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+        .with_context(|| format!("Failed to restore {:?}", entry.output))?;
+    }
+    Ok(())
+}
+
+fn save_generation(generation_dir: &Path, generation: &Generation) -> Result<()> {
+    let path = generation_dir.join(GENERATION_FILE_NAME);
+    std::fs::write(&path, serde_json::to_string_pretty(generation)?)
+        .with_context(|| format!("Failed to write the generation {:?}", path))
+}
+
+fn last_generation_dir(dest_base: &Path) -> Result<Option<PathBuf>> {
+    Ok(generation_dirs(dest_base)?.pop())
+}
+
+fn prune_generations(dest_base: &Path) -> Result<()> {
+    let generation_dirs = generation_dirs(dest_base)?;
+    let old = generation_dirs.len().saturating_sub(KEPT_GENERATIONS);
+    for generation_dir in &generation_dirs[..old] {
+        std::fs::remove_dir_all(generation_dir)
+            .with_context(|| format!("Failed to remove {:?}", generation_dir))?;
+    }
+    Ok(())
+}
+
+/* Oldest first */
+fn generation_dirs(dest_base: &Path) -> Result<Vec<PathBuf>> {
+    let backups_dir = dest_base.join(BACKUPS_DIR_NAME);
+    if !backups_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut generations = Vec::new();
+    for entry in std::fs::read_dir(&backups_dir)? {
+        let path = entry?.path();
+        let timestamp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<u128>().ok());
+        if let Some(timestamp) = timestamp {
+            generations.push((timestamp, path));
+        }
+    }
+    generations.sort();
+    Ok(generations.into_iter().map(|(_, path)| path).collect())
+}
+
+/*
+ * Where the original of an output is kept, i.e. the file that was there
+ * before templar first wrote it. Originals outlive generations, which are
+ * removed when rolled back.
+ */
+pub(super) fn original_path(dest_base: &Path, output_path: &Path) -> PathBuf {
+    dest_base
+        .join(BACKUPS_DIR_NAME)
+        .join(ORIGINALS_DIR_NAME)
+        .join(hash(output_path.as_os_str().as_bytes()))
+}
+
+/* Like std::fs::create_dir_all, but sets the mode of the directories it creates */
+fn create_dir_all(path: &Path, mode: Option<u32>) -> Result<()> {
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return Ok(std::fs::create_dir_all(path)?),
+    };
+    let missing = path
+        .ancestors()
+        .take_while(|ancestor| !ancestor.exists())
+        .collect::<Vec<_>>();
+    for dir in missing.into_iter().rev() {
+        std::fs::create_dir(dir)?;
+        std::fs::set_permissions(dir, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/*
+ * Generations are named after the millisecond they are committed in. One
+ * committed in the same millisecond as another takes the next free number,
+ * so that it never reuses the other's directory.
+ */
+fn create_generation_dir(dest_base: &Path) -> Result<PathBuf> {
+    let backups_dir = dest_base.join(BACKUPS_DIR_NAME);
+    std::fs::create_dir_all(&backups_dir)?;
+    let mut name = timestamp();
+    loop {
+        let generation_dir = backups_dir.join(name.to_string());
+        match std::fs::create_dir(&generation_dir) {
+            Ok(()) => return Ok(generation_dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => name += 1,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {:?}", generation_dir))
+            }
+        }
+    }
+}
+
+fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
+
+/*
+ * Atomically replaces `to` with `from`. Renaming only works within a
+ * filesystem, otherwise `from` is copied next to `to` first.
+ */
+pub(super) fn move_into_place(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let file_name = to
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not a file", to))?;
+    let temp_path = to.with_file_name(format!(".{}.templar-tmp", file_name.to_string_lossy()));
+    copy(from, &temp_path)?;
+    std::fs::rename(&temp_path, to)?;
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+/* Like std::fs::copy, but copies symlinks instead of the files they point to */
+pub(super) fn copy(from: &Path, to: &Path) -> Result<()> {
+    if from.symlink_metadata()?.file_type().is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+    } else {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let root = TempDir::new("test_commit_and_rollback").unwrap();
+        let dest_base = root.path();
+        let replaced = dest_base.join("replaced");
+        let created = dest_base.join("dir/created");
+        let linked = dest_base.join("linked");
+        std::fs::write(&replaced, "old").unwrap();
+        let file = |contents: &[u8]| Artifact::File {
+            contents: contents.to_vec(),
+            dependencies: vec![],
+        };
+
+        let mut transaction = Transaction::new(dest_base);
+        transaction
+            .stage(&replaced, &file(b"new"), 0o644, None)
+            .unwrap();
+        transaction
+            .stage(&created, &file(b"created"), 0o755, Some(0o700))
+            .unwrap();
+        transaction
+            .stage(&linked, &Artifact::Symlink(replaced.clone()), 0o644, None)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&replaced).unwrap(), "old");
+        assert!(!created.exists());
+
+        transaction.commit(&Manifest::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&replaced).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&created).unwrap(), "created");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&created), 0o755);
+        assert_eq!(mode(&dest_base.join("dir")), 0o700);
+        assert_eq!(std::fs::read_link(&linked).unwrap(), replaced);
+        assert!(!dest_base.join(STAGING_DIR_NAME).exists());
+        assert!(Manifest::path(dest_base).exists());
+
+        let rolled_back = rollback(dest_base).unwrap().unwrap();
+        assert_eq!(
+            rolled_back,
+            vec![replaced.clone(), created.clone(), linked.clone()]
+        );
+        assert_eq!(std::fs::read_to_string(&replaced).unwrap(), "old");
+        assert!(!created.exists());
+        assert!(linked.symlink_metadata().is_err());
+        assert!(!Manifest::path(dest_base).exists());
+
+        assert_eq!(rollback(dest_base).unwrap(), None);
+    }
+
+    #[test]
+    fn test_commit_and_rollback_removal() {
+        let root = TempDir::new("test_commit_and_rollback_removal").unwrap();
+        let dest_base = root.path();
+        let removed = dest_base.join("removed");
+        std::fs::write(&removed, "orphan").unwrap();
+
+        let mut transaction = Transaction::new(dest_base);
+        transaction.stage_removal(&removed);
+        transaction.stage_removal(&dest_base.join("missing"));
+        transaction.commit(&Manifest::default()).unwrap();
+        assert!(!removed.exists());
+
+        assert_eq!(rollback(dest_base).unwrap(), Some(vec![removed.clone()]));
+        assert_eq!(std::fs::read_to_string(&removed).unwrap(), "orphan");
+    }
+
+    #[test]
+    fn test_old_generations_are_pruned() {
+        let root = TempDir::new("test_old_generations_are_pruned").unwrap();
+        let output = root.path().join("output");
+        for i in 0..KEPT_GENERATIONS + 2 {
+            let artifact = Artifact::File {
+                contents: i.to_string().into_bytes(),
+                dependencies: vec![],
+            };
+            let mut transaction = Transaction::new(root.path());
+            transaction.stage(&output, &artifact, 0o644, None).unwrap();
+            // Most are committed in the same millisecond as another
+            transaction.commit(&Manifest::default()).unwrap();
+        }
+        assert_eq!(
+            generation_dirs(root.path()).unwrap().len(),
+            KEPT_GENERATIONS
+        );
+
+        // The newest ones are kept
+        rollback(root.path()).unwrap();
+        let last = KEPT_GENERATIONS;
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), last.to_string());
+    }
+
+    #[test]
+    fn test_uncommitted_transaction() {
+        let root = TempDir::new("test_uncommitted_transaction").unwrap();
+        let output = root.path().join("output");
+
+        let mut transaction = Transaction::new(root.path());
+        let artifact = Artifact::File {
+            contents: b"output".to_vec(),
+            dependencies: vec![],
+        };
+        transaction.stage(&output, &artifact, 0o644, None).unwrap();
+        drop(transaction);
+
+        assert!(!output.exists());
+        assert!(!root.path().join(STAGING_DIR_NAME).exists());
+    }
+}
@@ -1,15 +1,60 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use dyn_clone::DynClone;
 use rlua::prelude::*;
 use std::fmt::Debug;
 
-use super::parser::ParserConfig;
-use super::Engine;
+use super::parser::{Parser, ParserConfig};
 
 pub(super) type DynDirective = Box<dyn Directive>;
 
+/* State shared by every directive of a template while it is being generated */
+#[derive(Debug, Clone, Default)]
+pub(super) struct Scope {
+    /* Files included by the template, transitively */
+    pub dependencies: Rc<RefCell<Vec<PathBuf>>>,
+    /* Directory of the template being generated, relative includes are looked up in it first */
+    pub dir: PathBuf,
+    /* Where relative includes are looked up next, in order */
+    pub include_dirs: Rc<Vec<PathBuf>>,
+}
+
+impl Scope {
+    fn resolve_include(&self, path: &str) -> Result<PathBuf> {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return Ok(path.to_path_buf());
+        }
+        // Includes used to be relative to the config directory (the current
+        // directory), which still works as a last resort
+        std::iter::once(&self.dir)
+            .chain(self.include_dirs.iter())
+            .map(|dir| dir.join(path))
+            .chain(std::iter::once(path.to_path_buf()))
+            .find(|candidate| candidate.exists())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Could not find {:?} in {:?}, the include directories {:?} or the config directory",
+                    path,
+                    self.dir,
+                    self.include_dirs
+                )
+            })
+    }
+
+    /* The scope of a template included from this one */
+    fn included(&self, path: &Path) -> Self {
+        Scope {
+            dependencies: self.dependencies.clone(),
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            include_dirs: self.include_dirs.clone(),
+        }
+    }
+}
+
 /* NOTE: Possibly unnecessary to even use DynClone at all?: RC should be faster, and even then I only
 have it so I can #[derive(Clone)] just in case I need it */
 dyn_clone::clone_trait_object!(Directive);
@@ -18,7 +63,7 @@ pub(super) trait Directive: Debug + DynClone {
     /* Generates a String from a Directive. */
     // NOTE: Possibly store ParserConfig inside Include and pass it from the parser?
     // NOTE: Possibly lua_context might be handled differently once I figure out how to to scopes
-    fn generate(&self, lua_context: &LuaContext) -> Result<String>;
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String>;
 
     // NOTE: Might be sensible to put this method in ParserConfig and possibly add another trait?
     // idk lets keep it simple for now
@@ -44,13 +89,13 @@ pub(super) trait Directive: Debug + DynClone {
 //}
 
 impl Directive for String {
-    fn generate(&self, _: &LuaContext) -> Result<String> {
+    fn generate(&self, _: &LuaContext, _: &Scope) -> Result<String> {
         Ok(self.clone())
     }
 }
 
 impl Directive for &str {
-    fn generate(&self, _: &LuaContext) -> Result<String> {
+    fn generate(&self, _: &LuaContext, _: &Scope) -> Result<String> {
         Ok(self.to_string())
     }
 }
@@ -62,10 +107,10 @@ pub(super) struct If {
 }
 
 impl Directive for If {
-    fn generate(&self, lua_context: &LuaContext) -> Result<String> {
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String> {
         let condition_result = lua_context.load(&self.condition).eval::<bool>()?;
         if condition_result {
-            self.blocks.generate(lua_context)
+            self.blocks.generate(lua_context, scope)
         } else {
             Ok("".to_string())
         }
@@ -80,12 +125,12 @@ pub(super) struct IfElse {
 }
 
 impl Directive for IfElse {
-    fn generate(&self, lua_context: &LuaContext) -> Result<String> {
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String> {
         let condition_result = lua_context.load(&self.condition).eval::<bool>()?;
         if condition_result {
-            self.if_blocks.generate(lua_context)
+            self.if_blocks.generate(lua_context, scope)
         } else {
-            self.else_blocks.generate(lua_context)
+            self.else_blocks.generate(lua_context, scope)
         }
     }
 }
@@ -97,12 +142,23 @@ pub(super) struct Include {
 }
 
 impl Directive for Include {
-    fn generate(&self, _lua_context: &LuaContext) -> Result<String> {
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String> {
         // TODO: Paths are handled by the conductor. Including directly from here is hacky
-        let engine = super::Trebuchet::new(self.parser_config.clone());
-        let path = PathBuf::from(self.path.clone());
-        let template_str = std::fs::read_to_string(path.as_path())?;
-        engine.process_template_str(template_str.as_str())
+        let parser = Parser {
+            config: self.parser_config.clone(),
+        };
+        let path = scope
+            .resolve_include(&self.path)
+            .with_context(|| format!("Failed to include {:?}", self.path))?;
+        let template_str = std::fs::read_to_string(path.as_path())
+            .with_context(|| format!("Failed to include {:?}", path))?;
+        let path = path.canonicalize().unwrap_or(path);
+        scope.dependencies.borrow_mut().push(path.clone());
+        // Included templates share the Lua state of the template including them,
+        // but their own includes are relative to them
+        parser
+            .parse_template_str(template_str.as_str())?
+            .generate(lua_context, &scope.included(&path))
     }
 }
 
@@ -114,8 +170,8 @@ pub(super) struct Transform {
 }
 
 impl Directive for Transform {
-    fn generate(&self, lua_context: &LuaContext) -> Result<String> {
-        let blocks = self.blocks.generate(lua_context)?;
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String> {
+        let blocks = self.blocks.generate(lua_context, scope)?;
         lua_context.globals().set(self.input_name.clone(), blocks)?;
         let r = lua_context.load(&self.transform).eval::<String>()?;
         lua_context.globals().set(self.input_name.clone(), LuaNil)?;
@@ -124,10 +180,10 @@ impl Directive for Transform {
 }
 
 impl Directive for Vec<DynDirective> {
-    fn generate(&self, lua_context: &LuaContext) -> Result<String> {
+    fn generate(&self, lua_context: &LuaContext, scope: &Scope) -> Result<String> {
         let mut result = String::new();
         for block in self {
-            result.push_str(&block.generate(lua_context)?);
+            result.push_str(&block.generate(lua_context, scope)?);
         }
         Ok(result.to_string())
     }
@@ -147,7 +203,7 @@ mod tests {
     fn test_directive_str() {
         let directive = "some text";
         Lua::new().context(|lua_context| {
-            let result = directive.generate(&lua_context).unwrap();
+            let result = directive.generate(&lua_context, &Scope::default()).unwrap();
             let expected = "some text".to_string();
             assert_eq!(result, expected);
         });
@@ -157,7 +213,7 @@ mod tests {
     fn test_directive_string() {
         let directive = "some text".to_string();
         Lua::new().context(|lua_context| {
-            let result = directive.generate(&lua_context).unwrap();
+            let result = directive.generate(&lua_context, &Scope::default()).unwrap();
             let expected = "some text".to_string();
             assert_eq!(result, expected);
         });
@@ -174,10 +230,14 @@ mod tests {
             blocks: vec![Box::new("some text".to_string())],
         };
         Lua::new().context(|lua_context| {
-            let result = directive_true.generate(&lua_context).unwrap();
+            let result = directive_true
+                .generate(&lua_context, &Scope::default())
+                .unwrap();
             let expected = "some text".to_string();
             assert_eq!(result, expected);
-            let result = directive_false.generate(&lua_context).unwrap();
+            let result = directive_false
+                .generate(&lua_context, &Scope::default())
+                .unwrap();
             let expected = "".to_string();
             assert_eq!(result, expected);
         });
@@ -196,10 +256,14 @@ mod tests {
             else_blocks: vec![Box::new("some more text".to_string())],
         };
         Lua::new().context(|lua_context| {
-            let result = directive_true.generate(&lua_context).unwrap();
+            let result = directive_true
+                .generate(&lua_context, &Scope::default())
+                .unwrap();
             let expected = "some text".to_string();
             assert_eq!(result, expected);
-            let result = directive_false.generate(&lua_context).unwrap();
+            let result = directive_false
+                .generate(&lua_context, &Scope::default())
+                .unwrap();
             let expected = "some more text".to_string();
             assert_eq!(result, expected);
         });
@@ -216,18 +280,59 @@ mod tests {
             "#
         );
         let mut file = std::fs::File::create(&path).unwrap();
-        file.write(file_contents.as_bytes()).unwrap();
+        file.write_all(file_contents.as_bytes()).unwrap();
 
         let parser_config: &ParserConfig = &PARSER_CONFIG;
         let directive = Include {
             path: path.to_string_lossy().to_string(),
             parser_config: parser_config.clone(),
         };
+        let scope = Scope::default();
         Lua::new().context(|lua_context| {
-            let result = directive.generate(&lua_context).unwrap();
+            let result = directive.generate(&lua_context, &scope).unwrap();
             let expected = "some text\n".to_string();
             assert_eq!(result, expected);
         });
+        let expected = vec![path.canonicalize().unwrap()];
+        assert_eq!(*scope.dependencies.borrow(), expected);
+    }
+
+    #[test]
+    fn test_directive_include_relative() {
+        let root = tempdir::TempDir::new("test_directive_include_relative").unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let templates = root.join("templates");
+        let partials = root.join("partials");
+        std::fs::create_dir_all(templates.join("nested")).unwrap();
+        std::fs::create_dir_all(&partials).unwrap();
+        std::fs::write(templates.join("nested/sibling"), "!!% include shared %!!").unwrap();
+        std::fs::write(partials.join("shared"), "shared").unwrap();
+        // Shadowed by the file next to the including template
+        std::fs::write(partials.join("sibling"), "wrong").unwrap();
+
+        let directive = Include {
+            path: "nested/sibling".to_string(),
+            parser_config: PARSER_CONFIG.clone(),
+        };
+        let scope = Scope {
+            dir: templates.clone(),
+            include_dirs: Rc::new(vec![partials.clone()]),
+            ..Default::default()
+        };
+        Lua::new().context(|lua_context| {
+            let result = directive.generate(&lua_context, &scope).unwrap();
+            assert_eq!(result, "shared");
+        });
+        let expected = vec![templates.join("nested/sibling"), partials.join("shared")];
+        assert_eq!(*scope.dependencies.borrow(), expected);
+
+        let directive = Include {
+            path: "missing".to_string(),
+            parser_config: PARSER_CONFIG.clone(),
+        };
+        Lua::new().context(|lua_context| {
+            assert!(directive.generate(&lua_context, &scope).is_err());
+        });
     }
 
     #[test]
@@ -238,7 +343,7 @@ mod tests {
             blocks: vec![Box::new("some text in RED".to_string())],
         };
         Lua::new().context(|lua_context| {
-            let result = directive.generate(&lua_context).unwrap();
+            let result = directive.generate(&lua_context, &Scope::default()).unwrap();
             let expected = "some text in #FF0000".to_string();
             assert_eq!(result, expected);
         });
@@ -1,61 +1,194 @@
+use std::{fmt::Debug, path::Path, rc::Rc};
+
+use self::directives::{Directive, Scope};
 use self::parser::ParserConfig;
-use super::engine::Engine;
+use super::engine::{lock_lua, Engine, RenderContext, Rendered, SharedLua};
 use anyhow::Result;
 use parser::Parser;
+use rlua::prelude::*;
 
 mod directives;
 pub mod parser; // TODO change visibility after abstracting ParserConfig
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct Trebuchet {
     parser: Parser, // TODO: maybe this should be a reference? Includes create new Treckbuckets
+    lua: SharedLua,
 }
 
 impl Default for Trebuchet {
     fn default() -> Self {
-        Trebuchet {
-            parser: Parser {
-                config: ParserConfig::default(),
-            },
-        }
+        Trebuchet::new(ParserConfig::default(), SharedLua::default())
+    }
+}
+
+impl Debug for Trebuchet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Trebuchet")
+            .field("parser", &self.parser)
+            .finish_non_exhaustive()
     }
 }
 
 impl Trebuchet {
     fn process_template_str(&self, template_str: &str) -> Result<String> {
+        Ok(self.render(template_str, &RenderContext::default())?.output)
+    }
+
+    fn render(&self, template_str: &str, context: &RenderContext) -> Result<Rendered> {
         let directives = self.parser.parse_template_str(template_str)?;
-        let mut output = String::new();
-        rlua::Lua::new().context(|lua_context| -> Result<()> {
-            for directive in directives {
-                let r = directive.generate(&lua_context)?;
-                output.push_str(r.as_str());
-            }
-            Ok(())
+        let scope = Scope {
+            dir: context
+                .template
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            include_dirs: Rc::new(context.include_dirs.clone()),
+            ..Default::default()
+        };
+        let output = lock_lua(&self.lua)?.context(|lua_context| {
+            run_isolated(&lua_context, context, || {
+                directives.generate(&lua_context, &scope)
+            })
         })?;
-        Ok(output)
+        let dependencies = scope.dependencies.take();
+        Ok(Rendered {
+            output,
+            dependencies,
+        })
     }
 }
 
+/*
+ * Saves the contents of the globals table and of every table reachable from
+ * it, returning a function that puts them back
+ */
+const SNAPSHOT_GLOBALS: &str = r#"
+    local next, rawset, type = next, rawset, type
+    local saved = {}
+    local function save(value)
+        if type(value) ~= "table" or saved[value] then
+            return
+        end
+        local contents = {}
+        saved[value] = contents
+        for key, field in next, value do
+            contents[key] = field
+            save(key)
+            save(field)
+        end
+    end
+    save(_G)
+    return function()
+        for table, contents in next, saved do
+            for key in next, table do
+                if contents[key] == nil then
+                    rawset(table, key, nil)
+                end
+            end
+            for key, field in next, contents do
+                rawset(table, key, field)
+            end
+        end
+    end
+"#;
+
+/*
+ * Runs f with templar.current set for the template. The Lua state is reused
+ * across templates, and may be the config's, so the globals and the tables
+ * reachable from them are put back as they were afterwards, whatever the
+ * template assigned. Tables only reachable through functions are not.
+ */
+pub(super) fn run_isolated<T>(
+    lua_context: &LuaContext,
+    context: &RenderContext,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let restore: LuaFunction = lua_context
+        .load(SNAPSHOT_GLOBALS)
+        .set_name("snapshot")?
+        .eval()?;
+    set_templar_table(lua_context, context)?;
+    let result = f();
+    restore.call::<_, ()>(())?;
+    result
+}
+
+/*
+ * Exposes the render context to templates as templar.current, and the rule's
+ * vars as the vars table. The templar tables are read-only, so that templates
+ * can't affect each other through them. vars is set anew for every template
+ */
+fn set_templar_table(lua_context: &LuaContext, context: &RenderContext) -> Result<()> {
+    let read_only: LuaFunction = lua_context
+        .load(
+            r#"
+            return function(name, data)
+                return setmetatable({}, {
+                    __index = data,
+                    __newindex = function() error(name .. " is read-only", 2) end,
+                    __len = function() return #data end,
+                    __pairs = function() return next, data, nil end,
+                })
+            end
+            "#,
+        )
+        .eval()?;
+
+    let parents = lua_context.create_sequence_from(context.parents.iter().map(String::as_str))?;
+    let current = lua_context.create_table()?;
+    current.set("id", context.id.as_str())?;
+    current.set(
+        "parents",
+        read_only.call::<_, LuaTable>(("templar.current.parents", parents))?,
+    )?;
+    current.set("basepath", context.basepath.to_string_lossy().as_ref())?;
+    current.set("template", context.template.to_string_lossy().as_ref())?;
+    current.set(
+        "destination",
+        context.destination.to_string_lossy().as_ref(),
+    )?;
+
+    let templar = lua_context.create_table()?;
+    templar.set(
+        "current",
+        read_only.call::<_, LuaTable>(("templar.current", current))?,
+    )?;
+    lua_context.globals().set(
+        "templar",
+        read_only.call::<_, LuaTable>(("templar", templar))?,
+    )?;
+    lua_context
+        .globals()
+        .set("vars", context.args.clone().to_lua(*lua_context)?)?;
+    Ok(())
+}
+
 impl Engine for Trebuchet {
     // NOTE: This method should ideally be on the trait Engine, so the conductor can call it for any engine
     // It should also take EngineArgs instead of ParserConfig
-    fn new(parser_config: ParserConfig) -> Self {
+    fn new(parser_config: ParserConfig, lua: SharedLua) -> Self {
         Trebuchet {
             parser: Parser {
                 config: parser_config,
             },
+            lua,
         }
     }
 
-    fn run(&self, input: &str) -> Result<String> {
-        self.process_template_str(input)
+    fn run(&self, input: &str, context: &RenderContext) -> Result<Rendered> {
+        self.render(input, context)
+    }
+
+    fn is_template(&self, input: &str) -> bool {
+        input.contains(self.parser.config.odelim.as_str())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Engine;
     use super::{parser::ParserConfig, Trebuchet};
+    use super::{Engine, RenderContext, SharedLua};
     use indoc::indoc;
 
     #[test]
@@ -84,7 +217,7 @@ mod tests {
             "#
         );
 
-        let trebuchet = Trebuchet::new(config.clone());
+        let trebuchet = Trebuchet::new(config.clone(), SharedLua::default());
         let output = trebuchet.process_template_str(template_str).unwrap();
         let expected = indoc!(
             r#"
@@ -107,9 +240,122 @@ mod tests {
             "#
         );
 
-        let trebuchet = Trebuchet::new(config);
+        let trebuchet = Trebuchet::new(config, SharedLua::default());
         let output = trebuchet.process_template_str(template_str).unwrap();
         let expected = "wooo".to_string();
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn test_trebuchet_globals_do_not_leak() {
+        let config = ParserConfig {
+            odelim: "<%".to_string(),
+            cdelim: "%>".to_string(),
+            ..Default::default()
+        };
+
+        let template_str = indoc!(
+            r#"
+                <% transform input %>
+                leaked = true
+                return input
+                <% to %>
+                text
+                <% end %>
+                <% if leaked == nil %>
+                clean
+                <% end %>
+            "#
+        );
+
+        let trebuchet = Trebuchet::new(config, SharedLua::default());
+        let output = trebuchet.process_template_str(template_str).unwrap();
+        assert_eq!(output, "text\n");
+        let output = trebuchet.process_template_str(template_str).unwrap();
+        assert_eq!(output, "text\n");
+
+        let template_str = "<% if leaked == nil %>\nclean\n<% end %>\n";
+        let output = trebuchet.process_template_str(template_str).unwrap();
+        assert_eq!(output, "clean\n");
+    }
+
+    #[test]
+    fn test_trebuchet_globals_are_restored() {
+        let config = ParserConfig {
+            odelim: "<%".to_string(),
+            cdelim: "%>".to_string(),
+            ..Default::default()
+        };
+        let lua = SharedLua::default();
+        lua.lock()
+            .unwrap()
+            .context(|lua_context| {
+                lua_context
+                    .load(r#"colors = { bg = "black" }; function shout(s) return s:upper() end"#)
+                    .exec()
+            })
+            .unwrap();
+        let trebuchet = Trebuchet::new(config, lua);
+
+        let template_str = indoc!(
+            r#"
+                <% transform input %>
+                shout = function(s) return s end
+                colors.bg = "white"
+                table.insert = nil
+                return input
+                <% to %>
+                text
+                <% end %>
+            "#
+        );
+        trebuchet.process_template_str(template_str).unwrap();
+
+        let template_str = indoc!(
+            r#"
+                <% transform input %>
+                return shout(colors.bg) .. type(table.insert)
+                <% to %>
+                <% end %>
+            "#
+        );
+        let output = trebuchet.process_template_str(template_str).unwrap();
+        assert_eq!(output, "BLACKfunction");
+    }
+
+    #[test]
+    fn test_trebuchet_current() {
+        let config = ParserConfig {
+            odelim: "<%".to_string(),
+            cdelim: "%>".to_string(),
+            ..Default::default()
+        };
+        let trebuchet = Trebuchet::new(config, SharedLua::default());
+        let context = RenderContext {
+            id: "child".to_string(),
+            parents: vec!["root".to_string(), "parent".to_string()],
+            basepath: "/base".into(),
+            template: "/base/file.conf".into(),
+            destination: "/dest/file.conf".into(),
+            ..Default::default()
+        };
+        let template_str = indoc!(
+            r#"
+            <% if templar.current.id == "child" and #templar.current.parents == 2 %>
+            child
+            <% end %>
+            <% if templar.current.parents[1] == "root" %>
+            root
+            <% end %>
+            <% if templar.current.destination == "/dest/file.conf" %>
+            dest
+            <% end %>
+            <% if pcall(function() templar.current.id = "other" end) %>
+            writable
+            <% end %>
+            "#
+        );
+        let output = trebuchet.run(template_str, &context).unwrap().output;
+        assert_eq!(output, "child\nroot\ndest\n");
+    }
 }
@@ -26,8 +26,9 @@ use nom::{
     sequence::{delimited, pair},
     IResult,
 };
+use serde::Serialize;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub(crate) struct ParserConfig {
     pub odelim: String,
     pub cdelim: String,
@@ -68,7 +69,7 @@ impl Parser {
     // impl<T, I, O> Parser<I, O> for T where T: FnMut(I) -> IResult<I, O> {}
     // Unfortunately, dyn Generator is not infered correctly, so we can't use it
 
-    pub(super) fn parse_template_str<'a>(&self, i: &'a str) -> anyhow::Result<Vec<DynDirective>> {
+    pub(super) fn parse_template_str(&self, i: &str) -> anyhow::Result<Vec<DynDirective>> {
         let r = many0(alt((
             template_block(&self.config),
             // Text
@@ -88,6 +89,31 @@ impl Parser {
     }
 }
 
+/*
+ * Splits the header off a template: a comment at its very start holding a Lua
+ * table, e.g. `!!% ## { cdelim = "%>" } %!!`. Returns the table and the rest
+ * of the template.
+ */
+pub(crate) fn split_header<'a>(c: &ParserConfig, i: &'a str) -> Option<(&'a str, &'a str)> {
+    let i = i.trim_start().strip_prefix(c.odelim.as_str())?;
+    let i = i.trim_start().strip_prefix(c.comment.as_str())?;
+    if !i.trim_start().starts_with('{') {
+        return None;
+    }
+    // The table itself may contain the closing delimiter, e.g. in a string
+    let end = i
+        .match_indices(c.cdelim.as_str())
+        .map(|(end, _)| end)
+        .find(|end| i[..*end].trim_end().ends_with('}'))?;
+    let rest = &i[end + c.cdelim.len()..];
+    // Neither is the line the header is on
+    let rest = rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))
+        .unwrap_or(rest);
+    Some((i[..end].trim(), rest))
+}
+
 // TODO: this is hacky
 /* like &str::trim_end but not removing \n's */
 fn trim_keep_newline(s: &str) -> String {
@@ -534,4 +560,22 @@ mod tests {
         let result = transform_line(&PARSER_CONFIG)(input);
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_split_header() {
+        let config = ParserConfig::default();
+        let template = indoc!(
+            r#"
+            !!% ## { cdelim = "%!!", mode = "600" } %!!
+            text
+            "#
+        );
+        let (header, rest) = split_header(&config, template).unwrap();
+        assert_eq!(header, r#"{ cdelim = "%!!", mode = "600" }"#);
+        assert_eq!(rest, "text\n");
+
+        assert!(split_header(&config, "text\n!!% ## { } %!!").is_none());
+        assert!(split_header(&config, "!!% if true %!!text!!% end %!!").is_none());
+        assert!(split_header(&config, "!!% ## not a table %!!").is_none());
+    }
 }
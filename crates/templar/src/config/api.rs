@@ -1,6 +1,7 @@
-use super::rawrule::RawRule;
-use anyhow::Result;
+use super::{rawconfig::EngineArgs, rawprofile::RawProfile, rawrule::RawRule};
+use anyhow::{anyhow, Result};
 use lua_export::*;
+use rlua::prelude::{LuaContext, LuaValue};
 
 pub(crate) use lua_functions::gen_lua_wrapper;
 pub(crate) use lua_functions::register_lua_api;
@@ -10,6 +11,7 @@ mod lua_functions {
     /*
      * NOTE: Every function must take a config as the first parameter at the moment
      */
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::sync::Mutex;
 
@@ -43,9 +45,54 @@ mod lua_functions {
         Ok(())
     }
 
+    /* Errors say where the rule is in the config, e.g. rules[2].rules[0].targets */
     #[lua_export]
-    fn add_rule_to_config(config: Arc<Mutex<RawConfig>>, rule: RawRule) -> Result<()> {
-        config.lock().unwrap().rules.push(rule); // unwrap?
+    fn add_rule_to_config<'lua>(
+        config: Arc<Mutex<RawConfig>>,
+        lua_context: LuaContext<'lua>,
+        rule: LuaValue<'lua>,
+    ) -> Result<()> {
+        let mut config = config.lock().unwrap(); // unwrap?
+        let path = format!("rules[{}]", config.rules.len());
+        let mut warnings = vec![];
+        let rule = RawRule::from_lua_at(rule, lua_context, &path, &mut warnings);
+        // Kept even if the rule is invalid, in case the config carries on with pcall
+        config.warnings.extend(warnings);
+        let rule = rule.map_err(|err| match err {
+            rlua::Error::RuntimeError(message) => anyhow!(message),
+            err => err.into(),
+        })?;
+        config.rules.push(rule);
+        Ok(())
+    }
+
+    #[lua_export]
+    fn set_dest_base(config: Arc<Mutex<RawConfig>>, dest_base: String) -> Result<()> {
+        config.lock().unwrap().dest_base = dest_base;
+        Ok(())
+    }
+
+    /* Includes not found next to the including template are looked up here, in order */
+    #[lua_export]
+    fn add_include_dir(config: Arc<Mutex<RawConfig>>, include_dir: String) -> Result<()> {
+        config.lock().unwrap().include_dirs.push(include_dir);
+        Ok(())
+    }
+
+    /* Vars every template can read, rules can add to them or override them */
+    #[lua_export]
+    fn set_vars(config: Arc<Mutex<RawConfig>>, vars: EngineArgs) -> Result<()> {
+        config.lock().unwrap().engine_args = vars;
+        Ok(())
+    }
+
+    /* Profiles by name, one of which is picked by --profile or the hostname */
+    #[lua_export]
+    fn set_profiles(
+        config: Arc<Mutex<RawConfig>>,
+        profiles: BTreeMap<String, RawProfile>,
+    ) -> Result<()> {
+        config.lock().unwrap().profiles = profiles;
         Ok(())
     }
 }
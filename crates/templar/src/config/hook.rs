@@ -0,0 +1,111 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Result;
+use rlua::prelude::{LuaContext, LuaFunction, LuaValue, ToLua};
+use rlua::{Lua, RegistryKey};
+
+/*
+ * A Lua function defined in the config. It lives in the registry of the Lua
+ * state the config was loaded with, which is needed to call it.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct Hook(Arc<RegistryKey>);
+
+impl PartialEq for Hook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Hook {}
+
+/* Lua functions a rule can define, called as its targets are processed */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Hooks {
+    /* Before the template is rendered */
+    pub before_render: Option<Hook>,
+    /* After the output was processed, whether or not it changed */
+    pub after_write: Option<Hook>,
+    /* After the output was processed, only if it changed */
+    pub on_change: Option<Hook>,
+}
+
+/* What a hook is called with, as a table */
+#[derive(Debug)]
+pub(crate) struct HookContext<'a> {
+    pub rule: &'a str,
+    pub template: &'a Path,
+    pub output: &'a Path,
+    /* Unknown before the output is written */
+    pub changed: Option<bool>,
+}
+
+impl Hook {
+    pub(crate) fn new<'lua>(
+        lua: LuaContext<'lua>,
+        function: LuaFunction<'lua>,
+    ) -> rlua::Result<Self> {
+        Ok(Hook(Arc::new(lua.create_registry_value(function)?)))
+    }
+
+    pub(crate) fn call(&self, lua: &Lua, context: &HookContext) -> Result<()> {
+        lua.context(|lua_context| {
+            let function: LuaFunction = lua_context.registry_value(&self.0)?;
+            let table = lua_context.create_table()?;
+            table.set("rule", context.rule)?;
+            table.set("template", context.template.to_string_lossy().as_ref())?;
+            table.set("output", context.output.to_string_lossy().as_ref())?;
+            table.set("changed", context.changed)?;
+            function.call::<_, ()>(table)
+        })?;
+        Ok(())
+    }
+
+    /* Calls the function with a path, returning the one it maps it to */
+    pub(crate) fn map_path(&self, lua: &Lua, path: &str) -> Result<String> {
+        Ok(lua.context(|lua_context| {
+            let function: LuaFunction = lua_context.registry_value(&self.0)?;
+            function.call::<_, String>(path)
+        })?)
+    }
+}
+
+impl<'lua> ToLua<'lua> for Hook {
+    fn to_lua(self, lua: LuaContext<'lua>) -> rlua::Result<LuaValue<'lua>> {
+        lua.registry_value(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::rawrule::RawRule;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_hook_call() {
+        let lua = Lua::new();
+        let hooks = lua
+            .context(|lua_context| {
+                lua_context
+                    .load(r#"{ targets = "*", on_change = function(ctx) called = ctx.rule .. ctx.output end }"#)
+                    .eval::<RawRule>()
+            })
+            .unwrap()
+            .hooks;
+        assert_eq!(hooks.before_render, None);
+
+        let output = PathBuf::from("/output");
+        let context = HookContext {
+            rule: "rule",
+            template: Path::new("/template"),
+            output: &output,
+            changed: Some(true),
+        };
+        hooks.on_change.unwrap().call(&lua, &context).unwrap();
+        let called = lua
+            .context(|lua_context| lua_context.globals().get::<_, String>("called"))
+            .unwrap();
+        assert_eq!(called, "rule/output");
+    }
+}
@@ -1,5 +1,7 @@
 pub(super) mod api; // TODO: Make this pub(super) once examples/ is not required
+pub(crate) mod hook;
 pub(crate) mod rawconfig;
+pub(crate) mod rawprofile;
 pub(crate) mod rawrule;
 
 // TODO: Reexport stuff
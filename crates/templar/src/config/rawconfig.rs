@@ -1,16 +1,154 @@
-use super::rawrule::RawRule;
+use super::{rawprofile::RawProfile, rawrule::RawRule};
 use anyhow::Result;
 use rlua::prelude::*;
-use std::{env, path::PathBuf};
+use serde_json::{Map, Number, Value};
+use std::{collections::BTreeMap, env, path::PathBuf};
 
 #[derive(Clone, Default, Debug)]
 pub(crate) struct RawConfig {
     pub rules: Vec<RawRule>,
     pub dest_base: String,
+    pub include_dirs: Vec<String>,
+    pub engine_args: EngineArgs,
+    pub profiles: BTreeMap<String, RawProfile>,
+    /* About mistakes in the config that are not errors, e.g. unknown rule settings */
+    pub warnings: Vec<String>,
+    /* The config file and the files it required */
+    pub files: Vec<PathBuf>,
 }
 
-// TODO:
-pub(super) struct EngineArgs {}
+/*
+ * What config.lua passes to the templates: for now the `vars` table, set
+ * globally and per rule. Kept as plain data, so that it can be merged, hashed
+ * and handed to engines that don't evaluate Lua.
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct EngineArgs {
+    pub vars: Map<String, Value>,
+}
+
+impl EngineArgs {
+    /* Other's vars take precedence, tables are merged key by key */
+    pub(crate) fn merged(&self, other: &EngineArgs) -> EngineArgs {
+        let mut vars = self.vars.clone();
+        merge_vars(&mut vars, &other.vars);
+        EngineArgs { vars }
+    }
+}
+
+fn merge_vars(vars: &mut Map<String, Value>, other: &Map<String, Value>) {
+    for (key, value) in other {
+        match (vars.get_mut(key), value) {
+            (Some(Value::Object(table)), Value::Object(other_table)) => {
+                merge_vars(table, other_table)
+            }
+            _ => {
+                vars.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for EngineArgs {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        match var_from_lua(lua_value)? {
+            Value::Object(vars) => Ok(EngineArgs { vars }),
+            // An empty table
+            Value::Array(array) if array.is_empty() => Ok(EngineArgs::default()),
+            _ => Err(vars_error("Expected vars to be a table with string keys")),
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for EngineArgs {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        var_to_lua(Value::Object(self.vars), lua)
+    }
+}
+
+/*
+ * Sequences become arrays and other tables objects, whose integer keys become
+ * strings. Functions and userdata can't be passed to templates.
+ */
+fn var_from_lua(lua_value: LuaValue) -> LuaResult<Value> {
+    Ok(match lua_value {
+        LuaValue::Nil => Value::Null,
+        LuaValue::Boolean(boolean) => Value::Bool(boolean),
+        LuaValue::Integer(integer) => Value::Number(integer.into()),
+        LuaValue::Number(number) => Value::Number(
+            Number::from_f64(number).ok_or_else(|| vars_error("Numbers in vars must be finite"))?,
+        ),
+        LuaValue::String(string) => Value::String(string.to_str()?.to_string()),
+        LuaValue::Table(table) => {
+            let len = table.raw_len();
+            let mut object = Map::new();
+            let mut is_sequence = true;
+            for pair in table.pairs::<LuaValue, LuaValue>() {
+                let (key, value) = pair?;
+                let key = match key {
+                    LuaValue::Integer(i) => {
+                        is_sequence &= (1..=len).contains(&i);
+                        i.to_string()
+                    }
+                    LuaValue::String(key) => {
+                        is_sequence = false;
+                        key.to_str()?.to_string()
+                    }
+                    _ => return Err(vars_error("Keys in vars must be strings or integers")),
+                };
+                object.insert(key, var_from_lua(value)?);
+            }
+            if is_sequence && len > 0 {
+                (1..=len)
+                    .map(|i| object.remove(&i.to_string()).unwrap_or(Value::Null))
+                    .collect()
+            } else {
+                Value::Object(object)
+            }
+        }
+        other => {
+            return Err(vars_error(&format!(
+                "Vars can't hold a {}",
+                other.type_name()
+            )))
+        }
+    })
+}
+
+fn var_to_lua(value: Value, lua: LuaContext) -> LuaResult<LuaValue> {
+    Ok(match value {
+        Value::Null => LuaValue::Nil,
+        Value::Bool(boolean) => LuaValue::Boolean(boolean),
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => LuaValue::Integer(integer),
+            None => LuaValue::Number(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(string) => string.to_lua(lua)?,
+        Value::Array(array) => LuaValue::Table(
+            lua.create_sequence_from(
+                array
+                    .into_iter()
+                    .map(|value| var_to_lua(value, lua))
+                    .collect::<LuaResult<Vec<_>>>()?,
+            )?,
+        ),
+        Value::Object(object) => {
+            let table = lua.create_table()?;
+            for (key, value) in object {
+                table.set(key, var_to_lua(value, lua)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+fn vars_error(message: &str) -> LuaError {
+    LuaError::FromLuaConversionError {
+        from: "LuaValue",
+        to: "EngineArgs",
+        message: Some(message.to_string()),
+    }
+}
 
 // TODO:
 pub fn require_config(lua: &Lua, config_file: PathBuf) -> Result<()> {
@@ -30,10 +168,62 @@ pub fn require_config(lua: &Lua, config_file: PathBuf) -> Result<()> {
     lua.context(|lua_context| {
         lua_context
             .load(&format!(r#"require "{}""#, config_filename))
-            .exec()?;
-        LuaResult::Ok(())
+            .exec()
+    })
+    .map_err(config_error)
+}
+
+/*
+ * The files of the modules loaded with require, the config itself included.
+ * They are looked up like require does, relative to the config directory
+ */
+pub fn required_files(lua: &Lua) -> Result<Vec<PathBuf>> {
+    let mut files = lua.context(|lua_context| {
+        let package: LuaTable = lua_context.globals().get("package")?;
+        let path: String = package.get("path")?;
+        let searchpath: LuaFunction = package.get("searchpath")?;
+        let mut files = Vec::new();
+        for pair in package
+            .get::<_, LuaTable>("loaded")?
+            .pairs::<String, LuaValue>()
+        {
+            let (name, _) = pair?;
+            if let Some(file) = searchpath.call::<_, Option<String>>((name, path.as_str()))? {
+                files.push(PathBuf::from(file));
+            }
+        }
+        LuaResult::Ok(files)
     })?;
-    Ok(())
+    for file in &mut files {
+        *file = file.canonicalize()?;
+    }
+    files.sort();
+    Ok(files)
+}
+
+/*
+ * Errors raised by the API functions come with the whole traceback, this
+ * keeps the cause and the line of the config it was raised at
+ */
+fn config_error(err: LuaError) -> anyhow::Error {
+    match err {
+        LuaError::CallbackError { traceback, cause } => {
+            let cause = match cause.as_ref() {
+                LuaError::RuntimeError(message) => message.clone(),
+                cause => cause.to_string(),
+            };
+            let location = traceback
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.starts_with("[C]") && !line.starts_with("[string"))
+                .find(|line| line.contains(": in "));
+            match location.and_then(|line| line.split(": in ").next()) {
+                Some(location) => anyhow::anyhow!("{}: {}", location, cause),
+                None => anyhow::anyhow!(cause),
+            }
+        }
+        err => err.into(),
+    }
 }
 
 #[cfg(test)]
@@ -48,7 +238,7 @@ mod tests {
     #[test]
     fn test_run_config() {
         let root = TempDir::new("test_run_config");
-        let root = root.ok().expect("Should have created a temp directory");
+        let root = root.expect("Should have created a temp directory");
 
         let base_path = root.path().join("base");
         let config_path = root.path().join("config.lua");
@@ -66,11 +256,34 @@ mod tests {
 
         File::create(&config_path)
             .unwrap()
-            .write(config.as_bytes())
+            .write_all(config.as_bytes())
             .unwrap();
 
         // Test starts here
         let lua = Lua::new();
         require_config(&lua, config_path).unwrap();
     }
+
+    #[test]
+    fn test_required_files() {
+        let root = TempDir::new("test_required_files").unwrap();
+        let root = root.path().canonicalize().unwrap();
+        std::fs::write(root.join("config.lua"), r#"require "helpers""#).unwrap();
+        std::fs::write(root.join("helpers.lua"), "function shout(s) end").unwrap();
+
+        let lua = Lua::new();
+        lua.context(|lua_context| {
+            lua_context
+                .load(&format!(
+                    r#"package.path = {:?}; require "config""#,
+                    root.join("?.lua")
+                ))
+                .exec()
+        })
+        .unwrap();
+        assert_eq!(
+            required_files(&lua).unwrap(),
+            vec![root.join("config.lua"), root.join("helpers.lua")]
+        );
+    }
 }
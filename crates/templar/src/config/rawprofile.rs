@@ -0,0 +1,57 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rlua::prelude::{FromLua, LuaContext, LuaValue, ToLua};
+
+use super::rawconfig::EngineArgs;
+use crate::hashmap;
+
+/*
+ * Settings for one of the machines the config is deployed on. Picked with
+ * --profile, or when its name or one of its hosts is the hostname.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub(crate) struct RawProfile {
+    /* Hostnames the profile is picked on, besides its name */
+    pub hosts: Vec<String>,
+    /* Merged into the global vars, set as `vars` */
+    pub engine_args: EngineArgs,
+    /* Enables or disables rules by id, overriding their `enabled` */
+    pub rules: BTreeMap<String, bool>,
+}
+
+impl<'lua> FromLua<'lua> for RawProfile {
+    fn from_lua(lua_value: rlua::Value<'lua>, _: rlua::Context<'lua>) -> rlua::Result<Self> {
+        if let LuaValue::Table(lua_table) = lua_value {
+            Ok(RawProfile {
+                hosts: lua_table
+                    .get::<_, Option<Vec<String>>>("hosts")?
+                    .unwrap_or_default(),
+                engine_args: lua_table
+                    .get::<_, Option<EngineArgs>>("vars")?
+                    .unwrap_or_default(),
+                rules: lua_table
+                    .get::<_, Option<BTreeMap<String, bool>>>("rules")?
+                    .unwrap_or_default(),
+            })
+        } else {
+            Err(rlua::Error::FromLuaConversionError {
+                to: "Profile",
+                from: "LuaValue",
+                message: Some("Expected profile to be a lua table".to_string()),
+            })
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for RawProfile {
+    fn to_lua(self, lua: rlua::Context<'lua>) -> rlua::Result<LuaValue<'lua>> {
+        let hashmap: HashMap<&str, LuaValue> = hashmap!(
+            "hosts" => self.hosts.to_lua(lua)?,
+            "vars" => self.engine_args.to_lua(lua)?,
+            "rules" => self.rules.to_lua(lua)?,
+        );
+        Ok(LuaValue::Table(LuaContext::create_table_from(
+            lua, hashmap,
+        )?))
+    }
+}
@@ -1,33 +1,219 @@
 use std::collections::HashMap;
 
-use rlua::prelude::{FromLua, LuaContext, LuaValue, ToLua};
+use rlua::prelude::{FromLua, LuaContext, LuaTable, LuaValue, ToLua};
 
+use super::{
+    hook::{Hook, Hooks},
+    rawconfig::EngineArgs,
+};
 use crate::hashmap;
 
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 pub(crate) struct RawRule {
     pub id: String,
-    pub targets: String,
+    /* Globs relative to the basepath, a single one or a list of them */
+    pub targets: Vec<String>,
     pub rules: Vec<RawRule>,
     pub basepath: String,
+    pub mode: Option<String>,
+    pub dir_mode: Option<String>,
+    pub deploy: Option<String>,
+    pub hooks: Hooks,
+    /* Inherited by the children that don't set it */
+    pub engine: Option<String>,
+    /* Inherited by the children, which can override single fields of it */
+    pub syntax: Option<RawSyntax>,
+    /* Merged into the parent's, set as `vars` */
+    pub engine_args: EngineArgs,
+    /* Disabled rules are skipped, along with their children. Profiles can override it */
+    pub enabled: Option<bool>,
+    /* Where the outputs go, relative to dest_base. Inherited by the children that don't set it */
+    pub dest: Option<String>,
+    /* Applied in order to the output paths. Inherited by the children that don't set them */
+    pub rename: Option<Vec<RawRename>>,
+    /* Globs of files under the basepath that are not targets, like in a .templarignore */
+    pub exclude: Vec<String>,
+}
+
+/* The name of a builtin rename, or a Lua function from path to path */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum RawRename {
+    Builtin(String),
+    Function(Hook),
+}
+
+/* Keywords and delimiters of the template syntax, unset ones are inherited */
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub(crate) struct RawSyntax {
+    pub odelim: Option<String>,
+    pub cdelim: Option<String>,
+    pub comment: Option<String>,
+    pub if_: Option<String>,
+    pub else_: Option<String>,
+    pub end: Option<String>,
+    pub include: Option<String>,
+    pub transform: Option<String>,
+    pub to: Option<String>,
+}
+
+/* The keys a rule table can have, anything else is most likely a typo */
+const RULE_KEYS: &[&str] = &[
+    "id",
+    "targets",
+    "rules",
+    "basepath",
+    "mode",
+    "dir_mode",
+    "deploy",
+    "before_render",
+    "after_write",
+    "on_change",
+    "engine",
+    "syntax",
+    "vars",
+    "enabled",
+    "dest",
+    "rename",
+    "exclude",
+];
+
+const SYNTAX_KEYS: &[&str] = &[
+    "odelim",
+    "cdelim",
+    "comment",
+    "if_",
+    "else_",
+    "end",
+    "include",
+    "transform",
+    "to",
+];
+
+impl RawRule {
+    /*
+     * Errors and warnings name where the rule is in the config, e.g.
+     * rules[2].rules[0], which is also the id of rules that don't set one.
+     * Warnings are added to warnings instead of printed, as the config may be
+     * run more than once
+     */
+    pub(crate) fn from_lua_at<'lua>(
+        lua_value: LuaValue<'lua>,
+        lua: LuaContext<'lua>,
+        path: &str,
+        warnings: &mut Vec<String>,
+    ) -> rlua::Result<Self> {
+        let fields = match lua_value {
+            LuaValue::Table(table) => Fields { table, lua, path },
+            other => return Err(type_error(path, "table", &other)),
+        };
+        let unknown_keys = fields.unknown_keys(RULE_KEYS)?;
+
+        // A misspelled targets is the likely cause, so the unknown keys are told too
+        let targets = match fields.strings("targets")? {
+            Some(targets) => targets,
+            None if unknown_keys.is_empty() => {
+                return Err(type_error(
+                    &fields.path("targets"),
+                    "string",
+                    &LuaValue::Nil,
+                ))
+            }
+            None => {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{}: expected string, got nil ({})",
+                    fields.path("targets"),
+                    unknown_keys.join("; ")
+                )))
+            }
+        };
+        warnings.extend(unknown_keys);
+        let rules = match fields.table("rules")? {
+            Some(rules) => rules
+                .sequence_values::<LuaValue>()
+                .enumerate()
+                .map(|(i, rule)| {
+                    let path = format!("{}[{}]", fields.path("rules"), i);
+                    RawRule::from_lua_at(rule?, lua, &path, warnings)
+                })
+                .collect::<rlua::Result<Vec<_>>>()?,
+            None => vec![],
+        };
+        let syntax = match fields.table("syntax")? {
+            Some(table) => {
+                let path = fields.path("syntax");
+                let syntax = Fields {
+                    table,
+                    lua,
+                    path: &path,
+                };
+                warnings.extend(syntax.unknown_keys(SYNTAX_KEYS)?);
+                Some(RawSyntax::from_fields(&syntax)?)
+            }
+            None => None,
+        };
+        let engine_args = match fields.get("vars")? {
+            LuaValue::Nil => EngineArgs::default(),
+            vars => EngineArgs::from_lua(vars, lua).map_err(|err| match err {
+                rlua::Error::FromLuaConversionError {
+                    message: Some(message),
+                    ..
+                } => rlua::Error::RuntimeError(format!("{}: {}", fields.path("vars"), message)),
+                err => err,
+            })?,
+        };
+        // A single rename or a list of them
+        let rename = match fields.get("rename")? {
+            LuaValue::Nil => None,
+            LuaValue::Table(renames) => Some(
+                renames
+                    .sequence_values::<LuaValue>()
+                    .enumerate()
+                    .map(|(i, rename)| {
+                        let path = format!("{}[{}]", fields.path("rename"), i);
+                        RawRename::from_lua_at(rename?, lua, &path)
+                    })
+                    .collect::<rlua::Result<Vec<_>>>()?,
+            ),
+            rename => Some(vec![RawRename::from_lua_at(
+                rename,
+                lua,
+                &fields.path("rename"),
+            )?]),
+        };
+
+        Ok(RawRule {
+            id: fields.string("id")?.unwrap_or_else(|| path.to_string()),
+            targets,
+            rules,
+            // The config directory
+            basepath: fields.string("basepath")?.unwrap_or_default(),
+            mode: fields.string("mode")?,
+            dir_mode: fields.string("dir_mode")?,
+            deploy: fields.string("deploy")?,
+            hooks: Hooks {
+                before_render: fields.hook("before_render")?,
+                after_write: fields.hook("after_write")?,
+                on_change: fields.hook("on_change")?,
+            },
+            engine: fields.string("engine")?,
+            syntax,
+            engine_args,
+            enabled: fields.boolean("enabled")?,
+            dest: fields.string("dest")?,
+            rename,
+            exclude: fields.strings("exclude")?.unwrap_or_default(),
+        })
+    }
 }
 
 impl<'lua> FromLua<'lua> for RawRule {
-    fn from_lua(lua_value: rlua::Value<'lua>, _: rlua::Context<'lua>) -> rlua::Result<Self> {
-        if let LuaValue::Table(lua_table) = lua_value {
-            Ok(RawRule {
-                id: lua_table.get("id")?,
-                targets: lua_table.get("targets")?,
-                rules: lua_table.get("rules")?,
-                basepath: lua_table.get("basepath")?,
-            })
-        } else {
-            Err(rlua::Error::FromLuaConversionError {
-                to: "Rule",
-                from: "LuaValue",
-                message: Some("Expected rule to be a lua table".to_string()),
-            })
+    fn from_lua(lua_value: rlua::Value<'lua>, lua: rlua::Context<'lua>) -> rlua::Result<Self> {
+        let mut warnings = vec![];
+        let rule = RawRule::from_lua_at(lua_value, lua, "rule", &mut warnings)?;
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
         }
+        Ok(rule)
     }
 }
 
@@ -39,9 +225,325 @@ impl<'lua> ToLua<'lua> for RawRule {
             "targets" => self.targets.to_lua(lua)?,
             "rules" => self.rules.to_lua(lua)?,
             "basepath" => self.basepath.to_lua(lua)?,
+            "mode" => self.mode.to_lua(lua)?,
+            "dir_mode" => self.dir_mode.to_lua(lua)?,
+            "deploy" => self.deploy.to_lua(lua)?,
+            "before_render" => self.hooks.before_render.to_lua(lua)?,
+            "after_write" => self.hooks.after_write.to_lua(lua)?,
+            "on_change" => self.hooks.on_change.to_lua(lua)?,
+            "engine" => self.engine.to_lua(lua)?,
+            "syntax" => self.syntax.to_lua(lua)?,
+            "vars" => self.engine_args.to_lua(lua)?,
+            "enabled" => self.enabled.to_lua(lua)?,
+            "dest" => self.dest.to_lua(lua)?,
+            "rename" => self.rename.to_lua(lua)?,
+            "exclude" => self.exclude.to_lua(lua)?,
         );
         Ok(LuaValue::Table(LuaContext::create_table_from(
             lua, hashmap,
         )?))
     }
 }
+
+impl RawRename {
+    fn from_lua_at<'lua>(
+        lua_value: LuaValue<'lua>,
+        lua: LuaContext<'lua>,
+        path: &str,
+    ) -> rlua::Result<Self> {
+        match lua_value {
+            LuaValue::String(name) => Ok(RawRename::Builtin(name.to_str()?.to_string())),
+            LuaValue::Function(function) => Ok(RawRename::Function(Hook::new(lua, function)?)),
+            other => Err(type_error(path, "string or function", &other)),
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for RawRename {
+    fn from_lua(lua_value: rlua::Value<'lua>, lua: rlua::Context<'lua>) -> rlua::Result<Self> {
+        RawRename::from_lua_at(lua_value, lua, "rename")
+    }
+}
+
+impl<'lua> ToLua<'lua> for RawRename {
+    fn to_lua(self, lua: rlua::Context<'lua>) -> rlua::Result<LuaValue<'lua>> {
+        match self {
+            RawRename::Builtin(name) => name.to_lua(lua),
+            RawRename::Function(hook) => hook.to_lua(lua),
+        }
+    }
+}
+
+impl RawSyntax {
+    fn from_fields(fields: &Fields) -> rlua::Result<Self> {
+        Ok(RawSyntax {
+            odelim: fields.string("odelim")?,
+            cdelim: fields.string("cdelim")?,
+            comment: fields.string("comment")?,
+            if_: fields.string("if_")?,
+            else_: fields.string("else_")?,
+            end: fields.string("end")?,
+            include: fields.string("include")?,
+            transform: fields.string("transform")?,
+            to: fields.string("to")?,
+        })
+    }
+}
+
+/* Other keys are allowed, as template headers hold the syntax along with other settings */
+impl<'lua> FromLua<'lua> for RawSyntax {
+    fn from_lua(lua_value: rlua::Value<'lua>, lua: rlua::Context<'lua>) -> rlua::Result<Self> {
+        match lua_value {
+            LuaValue::Table(table) => RawSyntax::from_fields(&Fields {
+                table,
+                lua,
+                path: "syntax",
+            }),
+            other => Err(type_error("syntax", "table", &other)),
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for RawSyntax {
+    fn to_lua(self, lua: rlua::Context<'lua>) -> rlua::Result<LuaValue<'lua>> {
+        let hashmap: HashMap<&str, LuaValue> = hashmap!(
+            "odelim" => self.odelim.to_lua(lua)?,
+            "cdelim" => self.cdelim.to_lua(lua)?,
+            "comment" => self.comment.to_lua(lua)?,
+            "if_" => self.if_.to_lua(lua)?,
+            "else_" => self.else_.to_lua(lua)?,
+            "end" => self.end.to_lua(lua)?,
+            "include" => self.include.to_lua(lua)?,
+            "transform" => self.transform.to_lua(lua)?,
+            "to" => self.to.to_lua(lua)?,
+        );
+        Ok(LuaValue::Table(LuaContext::create_table_from(
+            lua, hashmap,
+        )?))
+    }
+}
+
+/* Reads the fields of a table, with errors that say where in the config they are */
+struct Fields<'a, 'lua> {
+    table: LuaTable<'lua>,
+    lua: LuaContext<'lua>,
+    path: &'a str,
+}
+
+impl<'a, 'lua> Fields<'a, 'lua> {
+    fn path(&self, key: &str) -> String {
+        format!("{}.{}", self.path, key)
+    }
+
+    fn get(&self, key: &str) -> rlua::Result<LuaValue<'lua>> {
+        self.table.get(key)
+    }
+
+    /* Numbers are taken as strings too, like Lua does (e.g. mode = 644) */
+    fn string(&self, key: &str) -> rlua::Result<Option<String>> {
+        match self.get(key)? {
+            LuaValue::Nil => Ok(None),
+            value @ (LuaValue::String(_) | LuaValue::Integer(_) | LuaValue::Number(_)) => {
+                Ok(Some(String::from_lua(value, self.lua)?))
+            }
+            other => Err(type_error(&self.path(key), "string", &other)),
+        }
+    }
+
+    /* A single string or a list of them */
+    fn strings(&self, key: &str) -> rlua::Result<Option<Vec<String>>> {
+        let table = match self.get(key)? {
+            LuaValue::Table(table) => table,
+            _ => return Ok(self.string(key)?.map(|string| vec![string])),
+        };
+        table
+            .sequence_values::<LuaValue>()
+            .enumerate()
+            .map(|(i, value)| match value? {
+                value @ (LuaValue::String(_) | LuaValue::Integer(_) | LuaValue::Number(_)) => {
+                    String::from_lua(value, self.lua)
+                }
+                other => Err(type_error(
+                    &format!("{}[{}]", self.path(key), i),
+                    "string",
+                    &other,
+                )),
+            })
+            .collect::<rlua::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    fn boolean(&self, key: &str) -> rlua::Result<Option<bool>> {
+        match self.get(key)? {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Boolean(boolean) => Ok(Some(boolean)),
+            other => Err(type_error(&self.path(key), "boolean", &other)),
+        }
+    }
+
+    fn table(&self, key: &str) -> rlua::Result<Option<LuaTable<'lua>>> {
+        match self.get(key)? {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Table(table) => Ok(Some(table)),
+            other => Err(type_error(&self.path(key), "table", &other)),
+        }
+    }
+
+    fn hook(&self, key: &str) -> rlua::Result<Option<Hook>> {
+        match self.get(key)? {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Function(function) => Ok(Some(Hook::new(self.lua, function)?)),
+            other => Err(type_error(&self.path(key), "function", &other)),
+        }
+    }
+
+    /* A warning for every key that is not one of the known ones */
+    fn unknown_keys(&self, known: &[&str]) -> rlua::Result<Vec<String>> {
+        let mut warnings = vec![];
+        for pair in self.table.clone().pairs::<LuaValue, LuaValue>() {
+            let key = match pair?.0 {
+                LuaValue::String(key) => key.to_str()?.to_string(),
+                other => format!("[{}]", other.type_name()),
+            };
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let warning = match closest(&key, known) {
+                Some(suggestion) => format!(
+                    "{} is not a known setting, did you mean {}?",
+                    self.path(&key),
+                    suggestion
+                ),
+                None => format!("{} is not a known setting, it is ignored", self.path(&key)),
+            };
+            warnings.push(warning);
+        }
+        warnings.sort();
+        Ok(warnings)
+    }
+}
+
+fn type_error(path: &str, expected: &str, got: &LuaValue) -> rlua::Error {
+    rlua::Error::RuntimeError(format!(
+        "{}: expected {}, got {}",
+        path,
+        expected,
+        got.type_name()
+    ))
+}
+
+/* The known key at the smallest edit distance from a typo, if it is close enough */
+fn closest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    let distance = |a: &str, b: &str| {
+        let b = b.chars().collect::<Vec<_>>();
+        let mut row = (0..=b.len()).collect::<Vec<_>>();
+        for (i, a) in a.chars().enumerate() {
+            let mut previous = row[0];
+            row[0] = i + 1;
+            for (j, b) in b.iter().enumerate() {
+                let substitution = previous + usize::from(a != *b);
+                previous = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+            }
+        }
+        row[b.len()]
+    };
+    known
+        .iter()
+        .map(|candidate| (distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    fn parse_rule(source: &str) -> rlua::Result<RawRule> {
+        parse_rule_warning(source).map(|(rule, _)| rule)
+    }
+
+    fn parse_rule_warning(source: &str) -> rlua::Result<(RawRule, Vec<String>)> {
+        Lua::new().context(|lua_context| {
+            let value = lua_context.load(source).eval::<LuaValue>()?;
+            let mut warnings = vec![];
+            let rule = RawRule::from_lua_at(value, lua_context, "rules[0]", &mut warnings)?;
+            Ok((rule, warnings))
+        })
+    }
+
+    #[test]
+    fn test_rule_defaults() {
+        let rule =
+            parse_rule(r#"{ targets = "*", rules = { { targets = { "a", "b" } } } }"#).unwrap();
+        assert_eq!(rule.id, "rules[0]");
+        assert_eq!(rule.basepath, "");
+        assert_eq!(rule.rules[0].id, "rules[0].rules[0]");
+        assert_eq!(rule.rules[0].targets, vec!["a", "b"]);
+        assert!(rule.rules[0].rules.is_empty());
+
+        let rule = parse_rule(r#"{ id = "main", targets = "*", mode = 644 }"#).unwrap();
+        assert_eq!(rule.id, "main");
+        assert_eq!(rule.mode.as_deref(), Some("644"));
+    }
+
+    #[test]
+    fn test_rule_errors() {
+        let error = |source: &str| parse_rule(source).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"{ targets = "*", rules = { { targets = "a" }, { rules = {} } } }"#),
+            "runtime error: rules[0].rules[1].targets: expected string, got nil"
+        );
+        assert_eq!(
+            error(r#"{ targets = { "a", true } }"#),
+            "runtime error: rules[0].targets[1]: expected string, got boolean"
+        );
+        assert_eq!(
+            error(r#"{ targets = "*", syntax = { odelim = {} } }"#),
+            "runtime error: rules[0].syntax.odelim: expected string, got table"
+        );
+        assert_eq!(
+            error(r#"{ targets = "*", on_change = "echo" }"#),
+            "runtime error: rules[0].on_change: expected function, got string"
+        );
+        assert_eq!(
+            error(r#"{ target = "*" }"#),
+            "runtime error: rules[0].targets: expected string, got nil \
+             (rules[0].target is not a known setting, did you mean targets?)"
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let warnings = Lua::new()
+            .context(|lua_context| {
+                let table = lua_context
+                    .load(r#"{ target = "*", basepath = ".", colour = "red" }"#)
+                    .eval::<LuaTable>()?;
+                let fields = Fields {
+                    table,
+                    lua: lua_context,
+                    path: "rules[0]",
+                };
+                fields.unknown_keys(RULE_KEYS)
+            })
+            .unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                "rules[0].colour is not a known setting, it is ignored",
+                "rules[0].target is not a known setting, did you mean targets?",
+            ]
+        );
+        // Unknown keys are not errors
+        let (_, warnings) =
+            parse_rule_warning(r#"{ targets = "*", rules = { { targets = "*", colour = 1 } } }"#)
+                .unwrap();
+        assert_eq!(
+            warnings,
+            vec!["rules[0].rules[0].colour is not a known setting, it is ignored"]
+        );
+    }
+}
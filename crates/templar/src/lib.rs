@@ -1,5 +1,4 @@
 #![allow(dead_code)]
-#![feature(drain_filter)]
 
 mod commands;
 pub mod conductor;
@@ -1,5 +1,4 @@
 #![allow(dead_code)]
-#![feature(drain_filter)]
 
 use anyhow::Context;
 
@@ -15,6 +14,10 @@ fn main() {
     if let Some(command) = opt.command {
         match &command {
             opt::TemplarCommand::Run(x) => commands::run(x),
+            opt::TemplarCommand::Watch(x) => commands::watch(x),
+            opt::TemplarCommand::Rollback(x) => commands::rollback(x),
+            opt::TemplarCommand::Clean(x) => commands::clean(x),
+            opt::TemplarCommand::Uninstall(x) => commands::uninstall(x),
             opt::TemplarCommand::Generate(x) => commands::generate(x),
         }
         .with_context(|| format!("Failed to execute command: {:?}", command))
@@ -22,6 +22,14 @@ pub(super) fn from_args() -> Opt {
 pub enum TemplarCommand {
     /// Run templar
     Run(Run),
+    /// Run templar, and again whenever a template or the config changes
+    Watch(Watch),
+    /// Restore the outputs replaced by the last run
+    Rollback(Rollback),
+    /// Remove the outputs that no rule produces anymore
+    Clean(Clean),
+    /// Remove every output, restoring the files they replaced
+    Uninstall(Uninstall),
     /// Generate the lua module for Templar
     Generate(Generate),
 }
@@ -38,4 +46,84 @@ pub struct Run {
     /// Path to the file to generate
     #[structopt(short, long)]
     pub config_path: Option<PathBuf>,
+
+    /// Render everything and show what would change, without writing
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Overwrite outputs even if they were edited by hand or not written by templar
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Also remove the outputs that no rule produces anymore
+    #[structopt(long)]
+    pub prune: bool,
+
+    /// Number of templates to render in parallel (defaults to one per CPU)
+    #[structopt(short, long, default_value = "0", hide_default_value = true)]
+    pub jobs: usize,
+
+    /// Profile to use, instead of the one matching the hostname
+    #[structopt(long)]
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Watch {
+    /// Path to the config file
+    #[structopt(short, long)]
+    pub config_path: Option<PathBuf>,
+
+    /// Number of templates to render in parallel (defaults to one per CPU)
+    #[structopt(short, long, default_value = "0", hide_default_value = true)]
+    pub jobs: usize,
+
+    /// Profile to use, instead of the one matching the hostname
+    #[structopt(long)]
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Rollback {
+    /// Path to the config file
+    #[structopt(short, long)]
+    pub config_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Clean {
+    /// Path to the config file
+    #[structopt(short, long)]
+    pub config_path: Option<PathBuf>,
+
+    /// Remove the outputs without asking for confirmation
+    #[structopt(short, long)]
+    pub yes: bool,
+
+    /// Also remove outputs that were edited by hand
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Profile to use, instead of the one matching the hostname
+    #[structopt(long)]
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Uninstall {
+    /// Path to the config file
+    #[structopt(short, long)]
+    pub config_path: Option<PathBuf>,
+
+    /// Remove the outputs without asking for confirmation
+    #[structopt(short, long)]
+    pub yes: bool,
+
+    /// Also remove outputs that were edited by hand
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Profile to use, instead of the one matching the hostname
+    #[structopt(long)]
+    pub profile: Option<String>,
 }